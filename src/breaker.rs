@@ -0,0 +1,92 @@
+// redis熔断器
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use log::{error, info};
+
+/// 熔断器状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    Closed, //正常访问redis
+    Open, //redis不健康，全部走本地限流
+    HalfOpen //冷却结束，放一次检测试探redis
+}
+
+/// 连续失败达到阈值后打开，冷却后半开试探，试探成功关闭、失败重新打开
+pub struct Breaker {
+    threshold: u32, //连续失败次数阈值
+    cooldown: Duration, //打开后的冷却时间
+    inner: Mutex<(BreakerState, u32, Instant)> //(状态, 连续失败次数, 进入当前状态的时间)
+}
+
+impl Breaker {
+    pub fn new(threshold: u32, cooldown: u64) -> Self
+    {
+        Breaker {
+            threshold: threshold.max(1),
+            cooldown: Duration::from_millis(cooldown),
+            inner: Mutex::new((BreakerState::Closed, 0, Instant::now()))
+        }
+    }
+
+    pub fn state(&self) -> BreakerState
+    {
+        self.inner.lock().unwrap().0
+    }
+
+    /// 本次能否访问redis，打开后冷却结束只放行一次试探
+    pub fn allow(&self) -> bool
+    {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.0 {
+            BreakerState::Closed => true,
+            // 试探未返回结果(如调用方取消)时，再过一个冷却时间重新试探
+            _ if inner.2.elapsed() >= self.cooldown => {
+                transit(&mut inner, BreakerState::HalfOpen);
+                true
+            }
+            _ => false
+        }
+    }
+
+    /// 访问成功，从打开或半开恢复时返回true
+    pub fn success(&self) -> bool
+    {
+        let mut inner = self.inner.lock().unwrap();
+        inner.1 = 0;
+        if inner.0 == BreakerState::Closed {
+            return false
+        }
+        transit(&mut inner, BreakerState::Closed);
+        true
+    }
+
+    /// 访问失败，试探失败或连续失败达到阈值时打开
+    pub fn failure(&self)
+    {
+        let mut inner = self.inner.lock().unwrap();
+        inner.1 += 1;
+        if inner.0 == BreakerState::HalfOpen || (inner.0 == BreakerState::Closed && inner.1 >= self.threshold) {
+            transit(&mut inner, BreakerState::Open);
+        }
+    }
+
+    /// 直接打开，用于连接失败
+    pub fn trip(&self)
+    {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.0 != BreakerState::Open {
+            transit(&mut inner, BreakerState::Open);
+        }
+    }
+}
+
+/// 切换状态并记录
+fn transit(inner: &mut (BreakerState, u32, Instant), state: BreakerState)
+{
+    match state {
+        BreakerState::Open => error!("[Limiter:breaker]{:?} -> {:?} after {} failures", inner.0, state, inner.1),
+        _ => info!("[Limiter:breaker]{:?} -> {:?}", inner.0, state)
+    }
+    *inner = (state, if state == BreakerState::Closed { 0 } else { inner.1 }, Instant::now());
+}
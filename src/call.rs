@@ -0,0 +1,119 @@
+// 限流脚本调用
+
+use redis::{ErrorKind, FromRedisValue, RedisResult, Script, ToRedisArgs, Value};
+use redis::aio::ConnectionLike;
+use crate::limiter::timed;
+use crate::types::Response;
+
+/// (限流次数, 已使用次数, 等待毫秒数, 窗口长度)
+pub type Usage = (u64, u64, u64, u64);
+
+/// 一次检测对应的脚本调用，可单独执行也可与其它检测并发执行
+pub struct Call {
+    script: &'static str,
+    keys: Vec<String>,
+    args: Vec<Vec<u8>>,
+    decode: Box<dyn FnOnce(Value) -> RedisResult<Response> + Send>
+}
+
+impl Call {
+    pub fn new(script: &'static str) -> Self
+    {
+        Call {
+            script,
+            keys: vec![],
+            args: vec![],
+            decode: Box::new(|_| Ok(Response::default()))
+        }
+    }
+
+    pub fn key(mut self, key: String) -> Self
+    {
+        self.keys.push(key);
+        self
+    }
+
+    pub fn arg<T: ToRedisArgs>(mut self, arg: T) -> Self
+    {
+        self.args.extend(arg.to_redis_args());
+        self
+    }
+
+    /// 设置脚本返回值的解析方式
+    pub fn decode<T, F>(mut self, f: F) -> Self
+        where T: FromRedisValue, F: FnOnce(T) -> Usage + Send + 'static
+    {
+        self.decode = Box::new(move |value| Ok(respond(f(T::from_redis_value(&value)?))));
+        self
+    }
+
+    /// 设置查询脚本返回值的解析方式，f额外给出距重置的毫秒数
+    pub fn decode_peek<T, F>(mut self, f: F) -> Self
+        where T: FromRedisValue, F: FnOnce(T) -> (Usage, u64) + Send + 'static
+    {
+        self.decode = Box::new(move |value| {
+            let (usage, reset) = f(T::from_redis_value(&value)?);
+            Ok(Response { reset, ..respond(usage) })
+        });
+        self
+    }
+
+    /// 设置多窗口脚本返回值的解析方式，f额外给出所用窗口的序号(1起)与距重置的毫秒数，被拒绝时序号记入结果
+    pub fn decode_windows<T, F>(mut self, f: F) -> Self
+        where T: FromRedisValue, F: FnOnce(T) -> (Usage, u64, u64) + Send + 'static
+    {
+        self.decode = Box::new(move |value| {
+            let (usage, quota, reset) = f(T::from_redis_value(&value)?);
+            let res = respond(usage);
+            Ok(Response { quota: if res.surplus == 0 { quota } else { 0 }, reset, ..res })
+        });
+        self
+    }
+
+    /// 单独执行
+    pub async fn invoke<C: ConnectionLike + Send>(self, conn: &mut C) -> RedisResult<Response>
+    {
+        let script = Script::new(self.script);
+        let mut invocation = script.prepare_invoke();
+        invocation.key(&self.keys);
+        for arg in self.args.iter() {
+            invocation.arg(arg.as_slice());
+        }
+        let value = invocation.invoke_async::<_, Value>(conn).await?;
+        (self.decode)(value)
+    }
+}
+
+/// 并发执行多个调用，返回与calls一一对应的结果，timeout(ms)为每个调用的超时，0为不限
+/// 各调用在连接的副本上独立执行，多路复用连接上仍合并发送，某个调用出错或超时不影响其它调用的结果
+/// 超时在各调用内部生效，超时的调用随之结束，不会在调用方按失败策略处理后仍继续计数
+pub async fn batch<C>(conn: &C, calls: Vec<Call>, timeout: u64) -> Vec<RedisResult<Response>>
+    where C: ConnectionLike + Clone + Send + 'static
+{
+    let tasks: Vec<_> = calls.into_iter()
+        .map(|call| {
+            let mut conn = conn.clone();
+            tokio::spawn(async move { timed(timeout, call.invoke(&mut conn)).await })
+        })
+        .collect();
+    let mut results = vec![];
+    for task in tasks {
+        results.push(task.await.unwrap_or_else(|err| Err((ErrorKind::ClientError, "脚本调用中断", err.to_string()).into())));
+    }
+    results
+}
+
+/// 由使用情况生成检测结果
+fn respond((total, used, retry_after, window): Usage) -> Response
+{
+    let surplus = total.saturating_sub(used);
+    Response {
+        total,
+        surplus,
+        retry_after,
+        window: if surplus == 0 { window } else { 0 },
+        quota: if surplus == 0 { 1 } else { 0 },
+        reset: 0,
+        error: None
+    }
+}
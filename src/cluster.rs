@@ -0,0 +1,328 @@
+// redis集群路由
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use log::{error, info};
+use redis::{Arg, Cmd, ConnectionAddr, ErrorKind, Pipeline, RedisConnectionInfo, RedisFuture, RedisResult, Value};
+use redis::aio::ConnectionLike;
+use crate::node::{Connector, NodeConnection};
+
+/// 集群slot总数
+const SLOTS: u16 = 16384;
+/// 重定向最多重试次数
+const RETRY: u32 = 3;
+/// 集群迁移中(TRYAGAIN/CLUSTERDOWN)重试前的等待(ms)
+const RETRY_WAIT: u64 = 100;
+
+/// 集群连接，按key的slot把命令发往对应主节点，可被多个任务clone共享
+/// slot迁移(MOVED)或节点故障时刷新slot表后重试，迁移中(ASK)时转发到目标节点
+/// 发往单个节点的pipeline出错时只刷新slot表，不重试
+#[derive(Clone)]
+pub struct ClusterConnection {
+    seeds: Vec<String>, //种子节点 host:port
+    info: RedisConnectionInfo, //各节点共用的认证信息
+    connector: Connector, //各节点共用的连接方式
+    slots: Arc<RwLock<Vec<(u16, u16, String)>>>, //(起始slot, 结束slot, 主节点地址)
+    nodes: Arc<RwLock<HashMap<String, NodeConnection>>>, //各节点的连接
+}
+
+/// 命令的发送目标
+enum Target {
+    Any,
+    All,
+    Slot(u16)
+}
+
+impl ClusterConnection {
+    /// 通过种子节点获取slot分布
+    pub async fn connect(seeds: Vec<String>, info: RedisConnectionInfo, connector: Connector) -> RedisResult<Self>
+    {
+        let conn = ClusterConnection {
+            seeds,
+            info,
+            connector,
+            slots: Arc::new(RwLock::new(vec![])),
+            nodes: Arc::new(RwLock::new(HashMap::new())),
+        };
+        conn.refresh().await?;
+        Ok(conn)
+    }
+
+    /// 所有主节点的连接
+    pub async fn masters(&self) -> RedisResult<Vec<NodeConnection>>
+    {
+        let mut conns = vec![];
+        for addr in self.master_addrs() {
+            conns.push(self.node(&addr).await?);
+        }
+        Ok(conns)
+    }
+
+    /// 重新读取slot分布，依次尝试已知节点和种子节点
+    async fn refresh(&self) -> RedisResult<()>
+    {
+        let mut addrs = self.master_addrs();
+        addrs.extend(self.seeds.iter().cloned());
+        let mut last = None;
+        for addr in addrs {
+            let result = match self.node(&addr).await {
+                Ok(mut conn) => redis::cmd("CLUSTER")
+                    .arg("SLOTS")
+                    .query_async::<_, Value>(&mut conn)
+                    .await
+                    .and_then(parse_slots),
+                Err(err) => Err(err)
+            };
+            match result {
+                Ok(slots) => {
+                    info!("[Limiter:cluster]refresh slots from {}, {} ranges", addr, slots.len());
+                    *self.slots.write().unwrap() = slots;
+                    return Ok(())
+                }
+                Err(err) => {
+                    error!("[Limiter:cluster]refresh slots from {} error:{}", addr, err);
+                    last = Some(err)
+                }
+            }
+        }
+        Err(last.unwrap_or_else(|| (ErrorKind::ClusterDown, "没有可用的集群节点").into()))
+    }
+
+    /// 获取节点的连接，首次使用时建立
+    async fn node(&self, addr: &str) -> RedisResult<NodeConnection>
+    {
+        if let Some(conn) = self.nodes.read().unwrap().get(addr).cloned() {
+            return Ok(conn)
+        }
+        let (host, port) = match addr.rsplit_once(':').and_then(|(h, p)| p.parse::<u16>().ok().map(|p| (h, p))) {
+            Some(node) => node,
+            None => return Err((ErrorKind::InvalidClientConfig, "集群节点地址不正确", addr.to_string()).into())
+        };
+        let conn = self.connector.connect(ConnectionAddr::Tcp(host.to_string(), port), &self.info).await?;
+        self.nodes.write().unwrap().insert(addr.to_string(), conn.clone());
+        Ok(conn)
+    }
+
+    /// 当前slot表中的主节点地址
+    fn master_addrs(&self) -> Vec<String>
+    {
+        let mut addrs: Vec<String> = self.slots.read().unwrap().iter().map(|s| s.2.clone()).collect();
+        addrs.sort();
+        addrs.dedup();
+        addrs
+    }
+
+    /// slot所在的主节点地址，无key的命令发往任一主节点
+    fn owner(&self, slot: Option<u16>) -> RedisResult<String>
+    {
+        let slots = self.slots.read().unwrap();
+        let found = match slot {
+            Some(slot) => slots.iter().find(|(start, end, _)| *start <= slot && slot <= *end),
+            None => slots.first()
+        };
+        match found {
+            Some((_, _, addr)) => Ok(addr.clone()),
+            None => Err((ErrorKind::ClusterDown, "slot未分配到节点").into())
+        }
+    }
+
+    /// 按命令的key路由
+    async fn route(&self, cmd: &Cmd) -> RedisResult<Value>
+    {
+        match target(cmd) {
+            Target::All => {
+                // 脚本加载等命令需在每个主节点执行
+                let mut value = Value::Nil;
+                for mut conn in self.masters().await? {
+                    value = conn.req_packed_command(cmd).await?;
+                }
+                Ok(value)
+            }
+            Target::Any => self.send(None, cmd).await,
+            Target::Slot(slot) => self.send(Some(slot), cmd).await
+        }
+    }
+
+    /// 发送到slot所在节点，处理重定向
+    async fn send(&self, slot: Option<u16>, cmd: &Cmd) -> RedisResult<Value>
+    {
+        let mut redirect: Option<String> = None;
+        let mut retry = 0;
+        loop {
+            let asking = redirect.is_some();
+            let addr = match redirect.take() {
+                Some(addr) => addr,
+                None => self.owner(slot)?
+            };
+            let mut conn = self.node(&addr).await?;
+            let result = if asking {
+                // ASKING只对连接上的下一条命令有效，与命令合并发送，避免共享连接上其它任务的命令插在中间
+                let mut pipe = redis::pipe();
+                pipe.cmd("ASKING").add_command(cmd.clone());
+                conn.req_packed_commands(&pipe, 1, 1).await.map(|mut values| values.pop().unwrap_or(Value::Nil))
+            } else {
+                conn.req_packed_command(cmd).await
+            };
+            match result {
+                Err(err) if retry < RETRY && retryable(&err) => {
+                    retry += 1;
+                    match err.kind() {
+                        ErrorKind::Ask => {
+                            redirect = err.redirect_node().map(|(host, port)| format!("{}:{}", host, port));
+                        }
+                        ErrorKind::TryAgain | ErrorKind::ClusterDown => {
+                            tokio::time::sleep(Duration::from_millis(RETRY_WAIT)).await;
+                        }
+                        _ => self.refresh().await?
+                    }
+                }
+                result => return result
+            }
+        }
+    }
+
+    /// pipeline中的命令都在同一节点时整体发送，否则逐条路由
+    async fn route_pipeline(&self, pipe: &Pipeline, offset: usize, count: usize) -> RedisResult<Vec<Value>>
+    {
+        let mut addrs = vec![];
+        for cmd in pipe.cmd_iter() {
+            match target(cmd) {
+                Target::Slot(slot) => addrs.push(self.owner(Some(slot))?),
+                Target::Any => (),
+                Target::All => addrs.push(String::new())
+            }
+        }
+        addrs.dedup();
+        if addrs.len() <= 1 && addrs.first().is_none_or(|a| !a.is_empty()) {
+            let addr = match addrs.pop() {
+                Some(addr) => addr,
+                None => self.owner(None)?
+            };
+            let mut conn = self.node(&addr).await?;
+            // 出错时无法确定哪些命令已执行，不能整体重放，刷新slot表后把错误交给调用方
+            let result = conn.req_packed_commands(pipe, offset, count).await;
+            if let Err(err) = &result {
+                if retryable(err) {
+                    error!("[Limiter:cluster]pipeline on {} redirected:{}", addr, err);
+                    // 刷新失败时已记录，仍返回原错误
+                    let _ = self.refresh().await;
+                }
+            }
+            return result
+        }
+        if offset > 0 {
+            // 事务无法拆分到多个节点
+            return Err((ErrorKind::CrossSlot, "事务中的key不在同一节点").into())
+        }
+        let mut values = vec![];
+        for cmd in pipe.cmd_iter() {
+            values.push(self.route(cmd).await?);
+        }
+        Ok(values.into_iter().take(count).collect())
+    }
+}
+
+impl ConnectionLike for ClusterConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value>
+    {
+        Box::pin(async move { self.route(cmd).await })
+    }
+
+    fn req_packed_commands<'a>(&'a mut self, cmd: &'a Pipeline, offset: usize, count: usize) -> RedisFuture<'a, Vec<Value>>
+    {
+        Box::pin(async move { self.route_pipeline(cmd, offset, count).await })
+    }
+
+    fn get_db(&self) -> i64
+    {
+        0
+    }
+}
+
+/// 计算key的slot，含hash tag时只取{}中的部分
+pub fn slot(key: &[u8]) -> u16
+{
+    let key = match key.iter().position(|b| *b == b'{') {
+        Some(open) => match key[open + 1..].iter().position(|b| *b == b'}') {
+            Some(len) if len > 0 => &key[open + 1..open + 1 + len],
+            _ => key
+        },
+        None => key
+    };
+    crc16(key) % SLOTS
+}
+
+/// CRC16/XMODEM，与redis集群一致
+fn crc16(data: &[u8]) -> u16
+{
+    let mut crc = 0_u16;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// 命令的发送目标，脚本按第一个KEYS路由
+fn target(cmd: &Cmd) -> Target
+{
+    let args: Vec<&[u8]> = cmd.args_iter()
+        .filter_map(|arg| match arg {
+            Arg::Simple(a) => Some(a),
+            Arg::Cursor => None
+        })
+        .collect();
+    let name = args.first().map(|a| a.to_ascii_uppercase()).unwrap_or_default();
+    let key = match name.as_slice() {
+        b"SCRIPT" | b"FLUSHALL" | b"FLUSHDB" => return Target::All,
+        b"EVAL" | b"EVALSHA" => match args.get(2) {
+            Some(n) if *n != b"0" => args.get(3),
+            _ => None
+        },
+        b"PING" | b"ASKING" | b"CLUSTER" | b"INFO" | b"TIME" | b"SCAN" | b"DBSIZE" => None,
+        _ => args.get(1)
+    };
+    match key {
+        Some(key) => Target::Slot(slot(key)),
+        None => Target::Any
+    }
+}
+
+/// 需要刷新路由后重试的错误
+fn retryable(err: &redis::RedisError) -> bool
+{
+    matches!(err.kind(), ErrorKind::Moved | ErrorKind::Ask | ErrorKind::TryAgain | ErrorKind::ClusterDown)
+        || err.is_connection_dropped()
+        || err.is_connection_refusal()
+}
+
+/// 解析CLUSTER SLOTS的结果
+fn parse_slots(value: Value) -> RedisResult<Vec<(u16, u16, String)>>
+{
+    let mut slots = vec![];
+    if let Value::Bulk(ranges) = value {
+        for range in ranges {
+            let range = match range {
+                Value::Bulk(range) if range.len() >= 3 => range,
+                _ => continue
+            };
+            let start: u16 = redis::from_redis_value(&range[0])?;
+            let end: u16 = redis::from_redis_value(&range[1])?;
+            if let Value::Bulk(master) = &range[2] {
+                if master.len() < 2 {
+                    continue
+                }
+                let host: String = redis::from_redis_value(&master[0])?;
+                let port: u16 = redis::from_redis_value(&master[1])?;
+                slots.push((start, end, format!("{}:{}", host, port)));
+            }
+        }
+    }
+    if slots.is_empty() {
+        return Err((ErrorKind::ClusterDown, "集群slot未分配").into())
+    }
+    Ok(slots)
+}
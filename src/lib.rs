@@ -1,32 +1,75 @@
+// db与statistician沿用原有写法，未做lint清理
+#[allow(noop_method_call, clippy::never_loop, clippy::io_other_error, clippy::ptr_arg)]
 mod db;
 mod v1;
+mod lua;
 mod redis;
+mod cluster;
+mod sentinel;
+mod node;
+mod breaker;
+mod permit;
+mod call;
 mod types;
 mod strategy;
+#[allow(deprecated, clippy::explicit_auto_deref, clippy::needless_range_loop, clippy::clone_on_copy, clippy::assign_op_pattern)]
 mod statistician;
 
 pub mod limiter {
     use std::collections::HashMap;
     use std::io::{Error, ErrorKind};
+    use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::future::Future;
+    use std::time::Duration;
     use log::{error, info};
     use redis::Script;
-    use crate::{types::*, strategy, redis::RedisRepo, db::DBRepo};
+    use crate::{types::*, strategy, lua, call, redis::RedisRepo, db::DBRepo};
+    use crate::redis::RedisConnection;
+    use crate::call::Call;
+    use crate::breaker::Breaker;
+    use crate::strategy::Strategy;
     use crate::statistician::statistic;
     use crate::v1::V1;
 
+    pub use crate::statistician::report;
+    pub use crate::permit::Permit;
+    pub use crate::breaker::BreakerState;
+    pub use crate::types::{FailurePolicy, V1Config};
+
     pub type Strategies = HashMap<i64, String>;
     pub type Statistics = HashMap<(i64, String), (u64, u64)>;
+    /// 默认的redis key前缀
     const REDIS_KEY: &str = "limiter:bot_api";
+    /// 启用v1后探测redis的初始间隔(ms)，每次失败翻倍
+    const PROBE_MIN: u64 = 500;
+    /// 探测redis的最大间隔(ms)
+    const PROBE_MAX: u64 = 30_000;
+    /// 熔断器默认的连续失败次数阈值
+    const BREAKER_THRESHOLD: u32 = 5;
+    /// 熔断器默认的冷却时间(ms)
+    const BREAKER_COOLDOWN: u64 = 5_000;
+    /// redis熔断中或连接失败时结果中注明的错误
+    const UNAVAILABLE: &str = "redis不可用";
 
-    /// 限流器
+    /// 限流器，可放入Arc中由多个任务并发使用
     pub struct Limiter {
         default: u64, //默认全域限流次数
-        strategies: Strategies, //最后生成的策略组信息
-        stop: bool, //是否停止限流
-        statistic: Statistics,  //统计信息 (bot_id: api):(pass: limit)
-        redis: Option<RedisRepo>,  //redis实例
+        strategies: RwLock<Arc<Strategies>>, //最后生成的策略组信息，reset时整体替换
+        stop: AtomicBool, //是否停止限流
+        statistic: Mutex<Statistics>,  //统计信息 (bot_id: api):(pass: limit)
+        redis: Option<Arc<RedisRepo>>,  //redis实例
         db: Option<DBRepo>,  //db实例
-        v1: Option<V1>, //第一版限流器
+        v1: Arc<Mutex<Option<V1>>>, //第一版限流器，redis恢复后由探测任务撤下
+        v1_config: RwLock<V1Config>, //第一版限流器的设置，启用时使用
+        probing: Arc<AtomicBool>, //是否有探测任务在运行
+        breaker: Arc<Breaker>, //redis熔断器，打开时走第一版限流器
+        timeout: u64, //每次访问redis的超时(ms)，0为不限
+        failure: FailurePolicy, //redis出错时的处理策略
+        nodes: u64, //预计的节点数，本地限流时均分限流次数
+        adaptive: RwLock<Option<Adaptive>>, //自适应限流设置
+        namespace: String, //redis key前缀，多个限流器共用redis时互相隔离
+        server_time: bool, //是否以redis服务器时间计算窗口
     }
 
     impl Limiter {
@@ -34,19 +77,28 @@ pub mod limiter {
         pub fn new(default: u64) -> Self {
             Limiter {
                 default,
-                strategies: HashMap::new(),
-                stop: true,
-                statistic: HashMap::new(),
+                strategies: RwLock::new(Arc::new(HashMap::new())),
+                stop: AtomicBool::new(true),
+                statistic: Mutex::new(HashMap::new()),
                 redis: None,
                 db: None,
-                v1: None,
+                v1: Arc::new(Mutex::new(None)),
+                v1_config: RwLock::new(V1Config::default()),
+                probing: Arc::new(AtomicBool::new(false)),
+                breaker: Arc::new(Breaker::new(BREAKER_THRESHOLD, BREAKER_COOLDOWN)),
+                timeout: 0,
+                failure: FailurePolicy::default(),
+                nodes: 1,
+                adaptive: RwLock::new(None),
+                namespace: REDIS_KEY.to_string(),
+                server_time: false,
             }
         }
 
         /// 获取限流次数
         pub fn get_limit(&self, bot: i64, api: &str) -> Result<(u64, String), Error>
         {
-            let u = strategy::limit(&self.strategies, bot, api.to_string())?;
+            let u = strategy::limit(&self.strategies(), bot, api.to_string())?;
             if u.0==0 {
                 Ok((self.default, u.1))
             } else {
@@ -54,142 +106,535 @@ pub mod limiter {
             }
         }
 
-        /// 执行限流检测脚本 key就是api
-        pub async fn check(&mut self, bot: i64, api: &str, key: &str, limit: u64) -> Result<Response, Error>
+        /// 执行限流检测脚本 key就是api，每次消耗接口配置的默认值(未配置为1)
+        /// redis出错时按失败策略给出结果，错误记在结果的error中，只有策略配置错误时返回Err
+        /// 停止限流时直接放行，total与surplus均为limit
+        pub async fn check(&self, bot: i64, api: &str, key: &str, limit: u64) -> Result<Response, Error>
         {
-            if self.stop {
-                // 返回默认值表示未开启限流
-                return Ok(Response::default())
-            }
+            self.check_with(bot, api, key, limit, None).await
+        }
+
+        /// 执行限流检测脚本，本次调用消耗cost次
+        pub async fn check_weighted(&self, bot: i64, api: &str, key: &str, limit: u64, cost: u64) -> Result<Response, Error>
+        {
+            self.check_with(bot, api, key, limit, Some(cost)).await
+        }
 
-            // 若v1被启动，则不执行后续动作
-            if let Some(mut v1) = self.v1.take() {
-                println!("v1");
-                let res = v1.check(bot).await;
-                self.v1 = Some(v1);
-                statistic(&mut self.statistic, bot, api.to_string(), res.surplus!=0);
-                return Ok(res)
+        async fn check_with(&self, bot: i64, api: &str, key: &str, limit: u64, cost: Option<u64>) -> Result<Response, Error>
+        {
+            if self.stop.load(Ordering::Relaxed) {
+                // 未开启限流时直接放行
+                return Ok(Response::allow(limit))
             }
 
-            let conn = if let Some(my_redis) = self.redis.take() {
-                let conn = my_redis
-                    .get_connection()
-                    .map_err(|err| Error::new(ErrorKind::NotFound, err))?;
-                self.redis = Some(my_redis);
-                Some(conn)
+            let rule = strategy::find(&self.strategies(), bot)?;
+            let cost = cost.unwrap_or_else(|| rule.cost(api));
+
+            if let Some(mut conn) = self.connection().await {
+                // 走新版限流器
+                let factor = self.factor(&mut conn, bot, key).await;
+                match self.guard(self.call(bot, key, &rule, limit, factor, cost).invoke(&mut conn)).await {
+                    Ok(res) => {
+                        // 统计操作
+                        self.statistic(bot, api, res.surplus!=0);
+                        Ok(res)
+                    }
+                    Err(err) => {
+                        error!("[Limiter:lib]run lua error:{}", err);
+                        Ok(self.on_failure((bot, api, key, limit), &rule, factor, cost, err.to_string()).await)
+                    }
+                }
+            } else if self.redis.is_some() {
+                // redis熔断中或连接失败
+                Ok(self.on_failure((bot, api, key, limit), &rule, None, cost, UNAVAILABLE.to_string()).await)
             } else {
-                None
+                // 走初版限流器
+                Ok(self.check_v1((bot, api, key, limit), &rule, None, cost).await)
+            }
+        }
+
+        /// 查询bot/key的剩余次数与重置时间，不消耗次数也不计入统计
+        pub async fn peek(&self, bot: i64, key: &str, limit: u64) -> Result<Response, Error>
+        {
+            if self.stop.load(Ordering::Relaxed) {
+                return Ok(Response::allow(limit))
+            }
+            let rule = strategy::find(&self.strategies(), bot)?;
+            if let Some(mut conn) = self.connection().await {
+                let factor = self.factor(&mut conn, bot, key).await;
+                return self
+                    .guard(self.peek_call(bot, key, &rule, limit, factor).invoke(&mut conn))
+                    .await
+                    .map_err(|err| {
+                        error!("[Limiter:lib]run lua error:{}", err);
+                        Error::new(ErrorKind::Interrupted, "限流运行错误")
+                    })
+            }
+            // v1未启用时按空状态作答，查询不启用v1
+            let limit = self.local(limit);
+            let peek = |v1: &V1| match rule.algorithm {
+                Algorithm::TokenBucket { rate, burst } => {
+                    let (rate, burst) = strategy::bucket(v1.limit(limit), self.local(rate), self.local(burst));
+                    v1.peek_bucket(bot, key, rate, burst, rule.window)
+                }
+                _ => v1.peek(bot, key, limit, rule.window)
+            };
+            let res = match self.v1.lock().unwrap().as_ref() {
+                Some(v1) => peek(v1),
+                None => peek(&V1::idle(*self.v1_config.read().unwrap()))
             };
+            Ok(res)
+        }
 
-            if conn.is_none() {
-                // 走初版限流器
-                println!("v1 start");
-                info!("[Limiter.lib]setup v1 version");
-                if self.v1.is_none() {
-                    self.equip_v1();
+        /// 读取自适应系数，未配置adaptive或读取失败时为None
+        async fn factor(&self, conn: &mut RedisConnection, bot: i64, key: &str) -> Option<u64>
+        {
+            if self.adaptive.read().unwrap().is_none() {
+                return None
+            }
+            let mut cmd = redis::cmd("HGET");
+            cmd.arg(format!("{}:adaptive", self.bot_key(bot))).arg(key);
+            match self.guard(cmd.query_async::<_, Option<u64>>(conn)).await {
+                Ok(factor) => factor,
+                Err(err) => {
+                    error!("[Limiter:lib]read adaptive factor error:{}", err);
+                    None
                 }
-                if let Some(mut v1) = self.v1.take() {
-                    let res = v1.check(bot).await;
-                    self.v1 = Some(v1);
-                    statistic(&mut self.statistic, bot, api.to_string(), res.surplus!=0);
-                    Ok(res)
-                } else {
-                    Ok(Response::default())
+            }
+        }
+
+        /// 批量检测，items为(bot, api, key, limit)，含义同check，返回与items一一对应的结果
+        /// 各检测在多路复用连接上并发执行，某项出错时只有该项按失败策略处理，配置了adaptive时需多一次往返读取系数
+        pub async fn check_batch(&self, items: &[(i64, &str, &str, u64)]) -> Vec<Result<Response, Error>>
+        {
+            if self.stop.load(Ordering::Relaxed) {
+                return items.iter().map(|(.., limit)| Ok(Response::allow(*limit))).collect()
+            }
+            let strategies = self.strategies();
+            let mut rules = vec![];
+            for (bot, api, _, _) in items.iter() {
+                rules.push(strategy::find(&strategies, *bot).map(|rule| {
+                    let cost = rule.cost(api);
+                    (rule, cost)
+                }));
+            }
+
+            let mut conn = match self.connection().await {
+                Some(conn) => conn,
+                None => {
+                    // 没有redis时走初版限流器，redis不可用时按失败策略，本地执行无需合并
+                    let mut results = vec![];
+                    for (item, rule) in items.iter().zip(rules) {
+                        results.push(match rule {
+                            Ok((rule, cost)) if self.redis.is_some() =>
+                                Ok(self.on_failure(*item, &rule, None, cost, UNAVAILABLE.to_string()).await),
+                            Ok((rule, cost)) => Ok(self.check_v1(*item, &rule, None, cost).await),
+                            Err(err) => Err(err)
+                        });
+                    }
+                    return results
+                }
+            };
+
+            let factors = if self.adaptive.read().unwrap().is_some() {
+                let mut pipe = redis::pipe();
+                for (bot, _, key, _) in items.iter() {
+                    pipe.cmd("HGET").arg(format!("{}:adaptive", self.bot_key(*bot))).arg(*key);
+                }
+                match self.guard(pipe.query_async::<_, Vec<Option<u64>>>(&mut conn)).await {
+                    Ok(factors) => factors,
+                    Err(err) => {
+                        error!("[Limiter:lib]read adaptive factor error:{}", err);
+                        vec![None; items.len()]
+                    }
                 }
             } else {
-                // 走新版限流器
-                let mut conn = conn.unwrap();
-                let filed = format!("{}:{}", bot, key);
-                let now = chrono::Local::now().timestamp_millis();
-                // println!("now {}", now);
-                // 1.key 2.field 3.limit 4.instant
-                let lua = r#"
-                    local r = redis.call('HEXISTS', ARGV[1], ARGV[2])
-                    if(r==0)
-                    then
-                        local new_json = {
-                            ["instant"] = ARGV[4],
-                            ["current"] = 1
-                        }
-                        local new_str = cjson.encode(new_json)
-                        redis.call('HSET', ARGV[1], ARGV[2], new_str)
-                        return 0
-                    else
-                        local j_str = redis.call('HGET', ARGV[1], ARGV[2])
-                        local json = cjson.decode(j_str)
-
-                        local tmp = ARGV[4] - json.instant
-                        if(tmp>1000)
-                        then
-                            json.instant = ARGV[4]
-                            json.current = 1
-                            local new_str = cjson.encode(json)
-                            redis.call('HSET', ARGV[1], ARGV[2], new_str)
-                            return 0
-                        else
-                            local tmp = ARGV[3] - json.current
-                            if(tmp>0)
-                            then
-                                json.current = json.current + 1
-                                local new_str = cjson.encode(json)
-                                redis.call('HSET', ARGV[1], ARGV[2], new_str)
-                                return json.current - 1
-                            else
-                                return json.current
-                            end
-                        end
-                    end
-                "#;
-                let script = Script::new(lua);
-                let result = script
-                    .arg(REDIS_KEY)
-                    .arg(filed.as_str())
-                    .arg(limit)
-                    .arg(now)
-                    .invoke::<u64>(&mut conn);
-                let surplus = match result {
-                    Ok(u) => limit-u,
+                vec![None; items.len()]
+            };
+
+            // 策略出错的项不发往redis
+            let mut calls = vec![];
+            let mut pending = vec![];
+            for (((bot, _, key, limit), rule), factor) in items.iter().zip(rules).zip(factors.iter()) {
+                if let Ok((rule, cost)) = &rule {
+                    calls.push(self.call(*bot, key, rule, *limit, *factor, *cost));
+                }
+                pending.push(rule);
+            }
+            let count = calls.len();
+            let checked = call::batch(&conn, calls, self.timeout).await;
+            // 各项单独出错，只要有一项成功即说明redis可用
+            if count > 0 {
+                self.record(checked.iter().any(Result::is_ok));
+            }
+            let mut checked = checked.into_iter();
+            let mut results = vec![];
+            for ((item, rule), factor) in items.iter().zip(pending).zip(factors) {
+                let (rule, cost) = match rule {
+                    Ok(rule) => rule,
                     Err(err) => {
-                        error!("[Limiter:lib]run lua error:{}", err);
-                        return Err(Error::new(ErrorKind::Interrupted, "限流运行错误"))
+                        results.push(Err(err));
+                        continue
                     }
                 };
-                let res = Response {
-                    total: limit,
-                    surplus
+                results.push(Ok(match checked.next() {
+                    Some(Ok(res)) => {
+                        self.statistic(item.0, item.1, res.surplus!=0);
+                        res
+                    }
+                    Some(Err(err)) => {
+                        error!("[Limiter:lib]run lua error:{}", err);
+                        self.on_failure(*item, &rule, factor, cost, err.to_string()).await
+                    }
+                    None => self.on_failure(*item, &rule, factor, cost, "缺少脚本结果".to_string()).await
+                }));
+            }
+            results
+        }
+
+        /// redis出错时按失败策略给出结果，并在结果中注明错误
+        async fn on_failure(&self, item: (i64, &str, &str, u64), rule: &Strategy, factor: Option<u64>, cost: u64, err: String) -> Response
+        {
+            let (bot, api, _, limit) = item;
+            let limit = scale(limit, factor);
+            let res = match self.failure {
+                FailurePolicy::Allow => {
+                    // Response::default()的剩余次数为0，会被当作拒绝，这里明确给出放行
+                    self.statistic(bot, api, true);
+                    Response::allow(limit)
+                }
+                FailurePolicy::Deny => {
+                    self.statistic(bot, api, false);
+                    Response {
+                        total: limit,
+                        surplus: 0,
+                        retry_after: 0,
+                        window: rule.window,
+                        quota: 1,
+                        reset: 0,
+                        error: None
+                    }
+                }
+                FailurePolicy::Local => self.check_v1(item, rule, factor, cost).await
+            };
+            Response { error: Some(err), ..res }
+        }
+
+        /// 按策略生成检测脚本调用，factor为自适应系数，令牌桶的rate与burst一并换算
+        fn call(&self, bot: i64, key: &str, rule: &Strategy, limit: u64, factor: Option<u64>, cost: u64) -> Call
+        {
+            let limit = scale(limit, factor);
+            let bot_key = self.bot_key(bot);
+            let now = chrono::Local::now().timestamp_millis();
+            // println!("now {}", now);
+            let instant = self.instant(now);
+            let quotas = rule.quotas(limit);
+            let window = rule.window;
+            match rule.algorithm {
+                Algorithm::FixedWindow if !quotas.is_empty() => {
+                    let windows = self.windows(&bot_key, key, rule, limit, now);
+                    let mut call = Call::new(lua::COMPOUND);
+                    for (counter, ..) in windows.iter() {
+                        call = call.key(counter.clone());
+                    }
+                    call = call.arg(cost).arg(windows.len());
+                    for (_, limit, window, end) in windows.iter() {
+                        call = call.arg(limit).arg(window).arg(end);
+                    }
+                    if let Some((hash, field)) = self.legacy(bot, key) {
+                        call = call.key(hash.to_string()).arg(instant).arg(field);
+                    }
+                    call.decode_windows(move |(_, inx, used): (u64, u64, u64)| {
+                        let (_, limit, window, end) = windows[(inx as usize).saturating_sub(1)];
+                        // 日历窗口被拒绝时可准确给出到周期结束的等待时间
+                        let retry_after = if end > 0 && used >= limit { window } else { 0 };
+                        ((limit, used, retry_after, window), inx, 0)
+                    })
+                }
+                Algorithm::FixedWindow => {
+                    let mut call = Call::new(lua::FIXED_WINDOW)
+                        .key(format!("{}:fixed:{}", bot_key, key))
+                        .arg(limit)
+                        .arg(window)
+                        .arg(cost);
+                    if let Some((hash, field)) = self.legacy(bot, key) {
+                        call = call.key(hash.to_string()).arg(instant).arg(field);
+                    }
+                    call.decode(move |u: u64| (limit, u, 0, window))
+                }
+                Algorithm::SlidingLog => Call::new(lua::SLIDING_LOG)
+                    .key(format!("{}:log:{}", bot_key, key))
+                    .arg(limit)
+                    .arg(instant)
+                    .arg(window)
+                    .arg(cost)
+                    .decode(move |u: u64| (limit, u, 0, window)),
+                Algorithm::TokenBucket { rate, burst } => {
+                    let (rate, burst) = strategy::bucket(limit, scale(rate, factor), scale(burst, factor));
+                    Call::new(lua::TOKEN_BUCKET)
+                        .key(format!("{}:bucket:{}", bot_key, key))
+                        .arg(rate)
+                        .arg(burst)
+                        .arg(instant)
+                        .arg(window)
+                        .arg(cost)
+                        .decode(move |u: u64| (burst, u, 0, window))
+                }
+                Algorithm::Gcra => Call::new(lua::GCRA)
+                    .key(format!("{}:gcra:{}", bot_key, key))
+                    .arg(limit)
+                    .arg(instant)
+                    .arg(window)
+                    .arg(cost)
+                    .decode(move |(u, retry_after): (u64, u64)| (limit, u, retry_after, window))
+            }
+        }
+
+        /// 走初版限流器，item为(bot, api, key, limit)，令牌桶策略在本地也按令牌桶执行
+        /// 未启用时先启用，检测与启用在同一把锁内完成，不会在中途被撤下
+        /// factor为已读到的自适应系数，没有redis时为None
+        async fn check_v1(&self, item: (i64, &str, &str, u64), rule: &Strategy, factor: Option<u64>, cost: u64) -> Response
+        {
+            let (bot, api, key, limit) = item;
+            let limit = self.local(scale(limit, factor));
+            let (res, wait) = {
+                let mut v1 = self.equip_v1();
+                let v1 = v1.as_mut().expect("v1已启用");
+                let res = match rule.algorithm {
+                    Algorithm::TokenBucket { rate, burst } => {
+                        let (rate, burst) = strategy::bucket(v1.limit(limit), self.local(scale(rate, factor)), self.local(scale(burst, factor)));
+                        v1.check_bucket(bot, key, rate, burst, rule.window, cost)
+                    }
+                    _ => v1.check(bot, key, limit, rule.window, cost)
                 };
-                // 统计操作
-                statistic(&mut self.statistic, bot, api.to_string(), res.surplus!=0);
-                Ok(res)
-            }
-        }
-
-        /// 执行清空缓存脚本
-        pub async fn clear(&mut self) -> Result<(), Error>
-        {
-            if !self.stop {
-                return Err(Error::new(ErrorKind::Other, "未关闭限流"))
-            }
-            if let Some(my_redis) = self.redis.take() {
-                let mut conn = my_redis
-                    .get_connection()
-                    .map_err(|err| Error::new(ErrorKind::NotFound, err))?;
-                self.redis = Some(my_redis);
-                let lua = r#"return redis.call('del', ARGV[1])"#;
-                let script = Script::new(lua);
-                let result = script
-                    .arg(REDIS_KEY)
-                    .invoke::<usize>(&mut conn);
-                match result {
-                    Ok(1) => Ok(()),
-                    _ => Err(Error::new(ErrorKind::Other, "清空失败"))
+                (res, v1.wait)
+            };
+            self.statistic(bot, api, res.surplus!=0);
+            // 一个短暂延时返回效果，不持有锁
+            if res.surplus==0 {
+                tokio::time::sleep(std::time::Duration::from_millis(wait)).await
+            }
+            res
+        }
+
+        /// 并发限流，limit为同时执行的上限，lease为名额的租约(ms)
+        /// 返回的许可在drop时归还名额，调用方崩溃时名额在租约到期后被回收，无名额时返回None
+        /// redis熔断中或连接失败时返回Err
+        pub async fn acquire(&self, bot: i64, api: &str, key: &str, limit: u64, lease: u64) -> Result<Option<Permit>, Error>
+        {
+            if self.stop.load(Ordering::Relaxed) {
+                return Ok(Some(Permit::new(Response::allow(limit), None)))
+            }
+            if self.redis.is_none() {
+                return Err(Error::new(ErrorKind::NotFound, "并发限流需要redis"))
+            }
+            let mut conn = self.connection().await.ok_or_else(|| Error::new(ErrorKind::NotFound, UNAVAILABLE))?;
+
+            let redis_key = format!("{}:inflight:{}", self.bot_key(bot), key);
+            let now = chrono::Local::now().timestamp_millis();
+            let member = Permit::member(now);
+            let instant = self.instant(now);
+            let script = Script::new(lua::ACQUIRE);
+            let mut invocation = script.prepare_invoke();
+            invocation.key(redis_key.as_str()).arg(limit).arg(instant).arg(lease).arg(member.as_str());
+            let used = match self.guard(invocation.invoke_async::<_, u64>(&mut conn)).await {
+                Ok(u) => u,
+                Err(err) => {
+                    error!("[Limiter:lib]run lua error:{}", err);
+                    return Err(Error::new(ErrorKind::Interrupted, "限流运行错误"))
+                }
+            };
+            let surplus = limit.saturating_sub(used);
+            self.statistic(bot, api, surplus!=0);
+            if surplus == 0 {
+                return Ok(None)
+            }
+            let res = Response {
+                total: limit,
+                surplus,
+                retry_after: 0,
+                window: 0,
+                quota: 0,
+                reset: 0,
+                error: None
+            };
+            Ok(Some(Permit::new(res, Some((conn, redis_key, member, self.timeout, self.breaker.clone())))))
+        }
+
+        /// 反馈下游调用结果，用于自适应调整该bot/api的限流次数，各节点通过redis共享
+        /// 返回调整后的系数(千分比)，未配置adaptive时不调整
+        pub async fn report_outcome(&self, bot: i64, api: &str, success: bool, latency: u64) -> Result<u64, Error>
+        {
+            let adaptive = match *self.adaptive.read().unwrap() {
+                Some(a) => a,
+                None => return Ok(1000)
+            };
+            let (_, key) = strategy::limit(&self.strategies(), bot, api.to_string())?;
+            if self.redis.is_none() {
+                return Err(Error::new(ErrorKind::NotFound, "自适应限流需要redis"))
+            }
+            let mut conn = self.connection().await.ok_or_else(|| Error::new(ErrorKind::NotFound, UNAVAILABLE))?;
+            let script = Script::new(lua::ADAPT);
+            let mut invocation = script.prepare_invoke();
+            invocation.key(format!("{}:adaptive", self.bot_key(bot)))
+                .arg(key)
+                .arg(success as u8)
+                .arg(latency)
+                .arg(adaptive.min)
+                .arg(adaptive.max)
+                .arg(adaptive.increase)
+                .arg(adaptive.decrease)
+                .arg(adaptive.latency);
+            self.guard(invocation.invoke_async::<_, u64>(&mut conn))
+                .await
+                .map_err(|err| {
+                    error!("[Limiter:lib]run lua error:{}", err);
+                    Error::new(ErrorKind::Interrupted, "限流运行错误")
+                })
+        }
+
+        /// 清空本限流器key前缀下的全部缓存，需先停止限流
+        /// 初版把所有计数存在名为limiter:bot_api的hash中(field为bot:key)，使用默认前缀时一并删除
+        pub async fn clear(&self) -> Result<(), Error>
+        {
+            if !self.stop.load(Ordering::Relaxed) {
+                return Err(Error::other("未关闭限流"))
+            }
+            let pattern = self.namespace_pattern();
+            let legacy = if self.namespace == REDIS_KEY { vec![REDIS_KEY.to_string()] } else { vec![] };
+            let count = self.remove(vec![pattern], legacy, vec![]).await?;
+            info!("[Limiter:lib]clear {} keys", count);
+            Ok(())
+        }
+
+        /// 重置一个bot的全部计数，限流运行中也可执行，返回删除的key数
+        pub async fn clear_bot(&self, bot: i64) -> Result<u64, Error>
+        {
+            self.remove(vec![self.bot_pattern(bot)], vec![], vec![]).await
+        }
+
+        /// 重置一个bot下某个key(接口)的计数，包括附加窗口、并发名额和自适应系数，返回删除的key数
+        pub async fn clear_key(&self, bot: i64, key: &str) -> Result<u64, Error>
+        {
+            let bot_key = self.bot_key(bot);
+            let keys = ["fixed", "log", "bucket", "gcra", "inflight"].iter()
+                .map(|kind| format!("{}:{}:{}", bot_key, kind, key))
+                .collect();
+            let fields = vec![(format!("{}:adaptive", bot_key), key.to_string())];
+            self.remove(self.key_patterns(bot, key), keys, fields).await
+        }
+
+        /// 按模式重置计数，pattern为key前缀之后的部分，如 {123}:fixed:* ，返回删除的key数
+        pub async fn clear_pattern(&self, pattern: &str) -> Result<u64, Error>
+        {
+            let pattern = format!("{}:{}", glob_escape(&self.namespace), pattern);
+            self.remove(vec![pattern], vec![], vec![]).await
+        }
+
+        /// 本前缀下全部bot计数的匹配模式"{ns}:{*"，不会匹配以本前缀开头的其它前缀
+        pub(crate) fn namespace_pattern(&self) -> String
+        {
+            format!("{}:{{*", glob_escape(&self.namespace))
+        }
+
+        /// 一个bot全部计数的匹配模式，bot带有{}，不会匹配以其开头的其它bot
+        pub(crate) fn bot_pattern(&self, bot: i64) -> String
+        {
+            format!("{}*", glob_escape(&self.bot_key(bot)))
+        }
+
+        /// 一个bot下某个key的附加窗口与日历窗口计数的匹配模式
+        pub(crate) fn key_patterns(&self, bot: i64, key: &str) -> Vec<String>
+        {
+            let bot_key = glob_escape(&self.bot_key(bot));
+            vec![
+                format!("{}:fixed:{}:*", bot_key, glob_escape(key)),
+                format!("{}:quota:{}:*", bot_key, glob_escape(key))
+            ]
+        }
+
+        /// 删除匹配patterns的key、指定的keys以及hash中的fields，返回删除的key数
+        async fn remove(&self, patterns: Vec<String>, mut keys: Vec<String>, fields: Vec<(String, String)>) -> Result<u64, Error>
+        {
+            let my_redis = match &self.redis {
+                Some(my_redis) => my_redis,
+                None => return Err(Error::other("清空失败"))
+            };
+            let mut conn = self.connection().await.ok_or_else(|| Error::new(ErrorKind::NotFound, UNAVAILABLE))?;
+            for pattern in patterns {
+                let found = self.guard(my_redis.scan(pattern.as_str())).await.map_err(|err| {
+                    error!("[Limiter:lib]scan {} error:{}", pattern, err);
+                    Error::other("清空失败")
+                })?;
+                keys.extend(found);
+            }
+            keys.sort();
+            keys.dedup();
+            if keys.is_empty() && fields.is_empty() {
+                return Ok(0)
+            }
+            // 集群中的key可能不在同一slot，逐个删除，合并在一个pipeline中
+            let mut pipe = redis::pipe();
+            for key in keys.iter() {
+                pipe.cmd("DEL").arg(key.as_str());
+            }
+            for (key, field) in fields.iter() {
+                pipe.cmd("HDEL").arg(key.as_str()).arg(field.as_str()).ignore();
+            }
+            match self.guard(pipe.query_async::<_, Vec<u64>>(&mut conn)).await {
+                Ok(deleted) => Ok(deleted.iter().sum()),
+                Err(err) => {
+                    error!("[Limiter:lib]del keys error:{}", err);
+                    Err(Error::other("清空失败"))
                 }
+            }
+        }
+
+        /// 设置redis key前缀，默认为limiter:bot_api，需在run之前设置
+        /// 前缀不能含有{或}，否则会打乱集群hash tag和清空时的匹配，此时保留原前缀
+        pub fn set_namespace(mut self, namespace: &str) -> Self {
+            if namespace.contains(['{', '}']) {
+                error!("[Limiter:lib]invalid namespace {}, braces are not allowed", namespace);
             } else {
-                Err(Error::new(ErrorKind::Other, "清空失败"))
+                self.namespace = namespace.to_string();
             }
+            self
+        }
+
+        /// 设置是否以redis服务器时间计算窗口，避免各节点时钟不一致导致窗口错乱
+        /// 日历窗口的周期仍按本机时间划分
+        pub fn set_server_time(mut self, server_time: bool) -> Self {
+            self.server_time = server_time;
+            self
         }
 
-        /// 2.设置repo
+        /// 设置每次访问redis的超时(ms)，超时按失败计入熔断器，0为不限
+        pub fn set_timeout(mut self, timeout: u64) -> Self {
+            self.timeout = timeout;
+            self
+        }
+
+        /// 设置redis出错时的处理策略，默认改用本地限流，出错时检测结果的error中注明错误
+        pub fn set_failure_policy(mut self, failure: FailurePolicy) -> Self {
+            self.failure = failure;
+            self
+        }
+
+        /// 设置预计的节点数，本地限流时各节点按策略限流次数/节点数限流，默认为1
+        pub fn set_nodes(mut self, nodes: u64) -> Self {
+            self.nodes = nodes.max(1);
+            self
+        }
+
+        /// 设置熔断器，连续失败threshold次后打开，cooldown(ms)后放一次检测试探redis
+        pub fn set_breaker(mut self, threshold: u32, cooldown: u64) -> Self {
+            self.breaker = Arc::new(Breaker::new(threshold, cooldown));
+            self
+        }
+
+        /// 2.设置repo，redis_url以逗号分隔多个节点或带cluster=1时按集群连接，redis+sentinel://开头时通过哨兵连接
+        /// rediss://开启TLS，ACL用户名、CA与客户端证书的写法见RedisRepo
+        #[allow(clippy::ptr_arg)]
         pub async fn set_repo(
             mut self,
             redis_url: &str,
@@ -198,7 +643,7 @@ pub mod limiter {
             db_username: &str,
             db_password: &str) -> Self {
             match RedisRepo::open(redis_url, redis_password) {
-                Ok(repo) => self.redis = Some(repo),
+                Ok(repo) => self.redis = Some(Arc::new(repo)),
                 Err(err) => error!("[limiter:lib]set redis repo error {:?}", err)
             }
             match DBRepo::new(db_hosts, db_username, db_password).await {
@@ -209,83 +654,376 @@ pub mod limiter {
         }
 
         /// 3.开启服务
-        pub async fn run(mut self) -> Result<Self, Error>
+        pub async fn run(self) -> Result<Self, Error>
         {
             let config = self.get_config_by_db().await?;
             let config = parse_config(config)?;
             let strategies = config.get_strategies()?;
-            self.set_strategies(strategies);
+            self.set_strategies(strategies, config.adaptive);
+            if let Some(v1) = config.v1 {
+                self.set_v1_limit(v1);
+            }
             self.start();
             Ok(self)
         }
 
         /// 4.重设服务
-        pub async fn reset(&mut self, config: String) -> Result<(), Error>
+        pub async fn reset(&self, config: String) -> Result<(), Error>
         {
             let _config = parse_config(config.clone())?;
             let strategies = _config.get_strategies()?;
-            self.set_strategies(strategies);
+            self.set_strategies(strategies, _config.adaptive);
+            if let Some(v1) = _config.v1 {
+                self.set_v1_limit(v1);
+            }
             self.start();
-            if let Some(repo) = self.db.take() {
+            if let Some(repo) = &self.db {
                 // version目前固定值为0.1
-                let _ = repo.write(config, "0.1".to_string()).await?;
-                self.db = Some(repo);
+                repo.write(config, "0.1".to_string()).await?;
             }
             Ok(())
         }
 
-        /// 设置strategies
-        fn set_strategies(&mut self, map: Strategies)
+        /// 第一版限流器的设置
+        pub fn get_v1_limit(&self) -> V1Config
+        {
+            match self.v1.lock().unwrap().as_ref() {
+                Some(v1) => v1.get_limit(),
+                None => *self.v1_config.read().unwrap()
+            }
+        }
+
+        /// 运行时调整第一版限流器，已启用时立即生效，返回实际生效的设置(延时最多200ms)
+        pub fn set_v1_limit(&self, config: V1Config) -> V1Config
+        {
+            let config = config.limited();
+            *self.v1_config.write().unwrap() = config;
+            if let Some(v1) = self.v1.lock().unwrap().as_mut() {
+                v1.set_limit(config);
+            }
+            config
+        }
+
+        /// 调整第一版限流器并写入db配置，重启或reset后仍然生效
+        pub async fn store_v1_limit(&self, config: V1Config) -> Result<V1Config, Error>
+        {
+            let config = self.set_v1_limit(config);
+            if let Some(repo) = &self.db {
+                let mut stored = parse_config(repo.read().await?)?;
+                stored.v1 = Some(config);
+                // version目前固定值为0.1
+                repo.write(serde_json::to_string(&stored)?, "0.1".to_string()).await?;
+            }
+            Ok(config)
+        }
+
+        /// 设置strategies，新策略生成好后整体替换，不阻塞正在检测的任务
+        fn set_strategies(&self, map: Strategies, adaptive: Option<Adaptive>)
         {
-            self.strategies = map;
+            *self.strategies.write().unwrap() = Arc::new(map);
+            *self.adaptive.write().unwrap() = adaptive;
         }
 
-        /// 停止限流
-        pub fn stop(&mut self) {
-            self.stop = true
+        /// 当前strategies
+        fn strategies(&self) -> Arc<Strategies>
+        {
+            self.strategies.read().unwrap().clone()
+        }
+
+        /// 停止限流，之后的检测直接放行
+        pub fn stop(&self) {
+            self.stop.store(true, Ordering::Relaxed)
         }
 
         /// 开启限流
-        fn start(&mut self) {
-            self.stop = false
+        fn start(&self) {
+            self.stop.store(false, Ordering::Relaxed)
         }
 
         /// 读取db配置
-        pub async fn get_config_by_db(&mut self) -> Result<String, Error>
+        pub async fn get_config_by_db(&self) -> Result<String, Error>
         {
             let mut config = String::new();
-            if let Some(repo) = self.db.take() {
+            if let Some(repo) = &self.db {
                 match repo.read().await {
                     Ok(c) => config = c,
                     Err(err) => error!("[Limiter:lib]read db error: {}", err)
                 }
-                self.db = Some(repo);
             }
             Ok(config)
         }
 
         /// 限流统计读取并清空
-        pub fn flush(&mut self) -> Statistics
+        pub fn flush(&self) -> Statistics
         {
-            let tmp = self.statistic.clone();
-            self.statistic = HashMap::new();
-            tmp
+            std::mem::take(&mut *self.statistic.lock().unwrap())
         }
 
-        /// 启用第一版限流器
-        fn equip_v1(&mut self)
+        /// 限流统计
+        fn statistic(&self, bot: i64, api: &str, allow: bool)
         {
-            self.v1 = Some(V1::new(self.default / 2))
+            statistic(&mut self.statistic.lock().unwrap(), bot, api.to_string(), allow)
+        }
+
+        /// 固定窗口及其附加窗口，(计数key, 限流次数, 窗口长度, 日历窗口结束时间)
+        /// 附加窗口的计数key为 {bot}:fixed:{key}:{窗口长度}，日历窗口为 {bot}:quota:{key}:{周期}，都带类型段以免与其它key重名
+        fn windows(&self, bot_key: &str, key: &str, rule: &Strategy, limit: u64, now: i64) -> Vec<(String, u64, u64, i64)>
+        {
+            let mut windows = vec![(format!("{}:fixed:{}", bot_key, key), limit, rule.window, 0)];
+            for q in rule.quotas(limit) {
+                match q.period_end(now) {
+                    Some((id, end)) => windows.push((format!("{}:quota:{}:{}", bot_key, key, id), q.limit, (end - now) as u64, end)),
+                    None => windows.push((format!("{}:fixed:{}:{}", bot_key, key, q.window), q.limit, q.window, 0))
+                }
+            }
+            windows
+        }
+
+        /// 按策略生成只读查询脚本调用，factor同call
+        fn peek_call(&self, bot: i64, key: &str, rule: &Strategy, limit: u64, factor: Option<u64>) -> Call
+        {
+            let limit = scale(limit, factor);
+            let bot_key = self.bot_key(bot);
+            let now = chrono::Local::now().timestamp_millis();
+            let instant = self.instant(now);
+            let window = rule.window;
+            match rule.algorithm {
+                Algorithm::FixedWindow => {
+                    let windows = self.windows(&bot_key, key, rule, limit, now);
+                    let mut call = Call::new(lua::PEEK);
+                    for (counter, ..) in windows.iter() {
+                        call = call.key(counter.clone());
+                    }
+                    call = call.arg("fixed").arg(instant).arg(window).arg(windows.len());
+                    for (_, limit, ..) in windows.iter() {
+                        call = call.arg(limit);
+                    }
+                    call.decode_windows(move |(inx, used, reset): (u64, u64, u64)| {
+                        let (_, limit, window, _) = windows[(inx as usize).saturating_sub(1)];
+                        ((limit, used, 0, window), inx, reset)
+                    })
+                }
+                Algorithm::SlidingLog => Call::new(lua::PEEK)
+                    .key(format!("{}:log:{}", bot_key, key))
+                    .arg("log")
+                    .arg(instant)
+                    .arg(window)
+                    .decode_peek(move |(_, used, reset): (u64, u64, u64)| ((limit, used, 0, window), reset)),
+                Algorithm::TokenBucket { rate, burst } => {
+                    let (rate, burst) = strategy::bucket(limit, scale(rate, factor), scale(burst, factor));
+                    Call::new(lua::PEEK)
+                        .key(format!("{}:bucket:{}", bot_key, key))
+                        .arg("bucket")
+                        .arg(instant)
+                        .arg(window)
+                        .arg(rate)
+                        .arg(burst)
+                        .decode_peek(move |(_, used, reset): (u64, u64, u64)| ((burst, used, 0, window), reset))
+                }
+                Algorithm::Gcra => Call::new(lua::PEEK)
+                    .key(format!("{}:gcra:{}", bot_key, key))
+                    .arg("gcra")
+                    .arg(instant)
+                    .arg(window)
+                    .arg(limit)
+                    .decode_peek(move |(_, used, reset): (u64, u64, u64)| ((limit, used, 0, window), reset))
+            }
+        }
+
+        /// bot的redis key前缀，以{bot}为hash tag，同一bot的key在集群中落在同一slot
+        fn bot_key(&self, bot: i64) -> String
+        {
+            format!("{}:{{{}}}", self.namespace, bot)
+        }
+
+        /// 初版计数所在的hash与field，迁移期间固定窗口的计数key不存在时从中读取初值，迁移结束后删除
+        /// 只在默认前缀时读取，初版只支持单节点，集群时不读取(hash与bot的key不在同一slot)
+        fn legacy(&self, bot: i64, key: &str) -> Option<(&'static str, String)>
+        {
+            match &self.redis {
+                Some(repo) if self.namespace == REDIS_KEY && !repo.is_cluster() => Some((REDIS_KEY, format!("{}:{}", bot, key))),
+                _ => None
+            }
+        }
+
+        /// 传给脚本的时间，0表示由脚本读取redis服务器时间
+        fn instant(&self, now: i64) -> i64
+        {
+            if self.server_time { 0 } else { now }
+        }
+
+        /// 启用第一版限流器，返回持有的锁，调用方在锁内使用，避免刚启用就被撤下
+        fn equip_v1(&self) -> MutexGuard<'_, Option<V1>>
+        {
+            let mut v1 = self.v1.lock().unwrap();
+            if v1.is_none() {
+                info!("[Limiter:lib]switch to v1 limiter");
+                *v1 = Some(V1::new(*self.v1_config.read().unwrap()))
+            }
+            v1
+        }
+
+        /// 本地限流次数，按预计节点数均分策略限流次数，令牌桶配置的rate和burst也用它均分
+        fn local(&self, limit: u64) -> u64
+        {
+            limit.div_ceil(self.nodes)
+        }
+
+        /// 是否正在使用第一版限流器
+        pub fn is_fallback(&self) -> bool
+        {
+            self.v1.lock().unwrap().is_some()
+        }
+
+        /// 熔断器状态
+        pub fn breaker(&self) -> BreakerState
+        {
+            self.breaker.state()
+        }
+
+        /// 熔断器允许时获取redis连接，没有redis、熔断中或连接失败时为None，此时走第一版限流器
+        async fn connection(&self) -> Option<RedisConnection>
+        {
+            match &self.redis {
+                Some(my_redis) if self.breaker.allow() => self
+                    .timed(my_redis.get_connection())
+                    .await
+                    .inspect_err(|err| self.fallback(err))
+                    .ok(),
+                _ => None
+            }
+        }
+
+        /// 按设置的超时访问redis
+        async fn timed<T>(&self, fut: impl Future<Output = redis::RedisResult<T>>) -> redis::RedisResult<T>
+        {
+            timed(self.timeout, fut).await
+        }
+
+        /// 按设置的超时访问redis，并把结果计入熔断器
+        async fn guard<T>(&self, fut: impl Future<Output = redis::RedisResult<T>>) -> redis::RedisResult<T>
+        {
+            let result = self.timed(fut).await;
+            self.record(result.is_ok());
+            result
+        }
+
+        /// 把一次访问redis的结果计入熔断器
+        fn record(&self, ok: bool)
+        {
+            if ok {
+                // 只在熔断恢复时撤下v1，熔断器关闭期间个别key出错启用的v1保留，避免反复启用丢失计数
+                if self.breaker.success() && self.v1.lock().unwrap().take().is_some() {
+                    info!("[Limiter:lib]redis recovered, switch back from v1 limiter");
+                }
+            } else {
+                self.breaker.failure()
+            }
+        }
+
+        /// redis连接失败，打开熔断器，并在后台探测redis
+        fn fallback(&self, err: &redis::RedisError)
+        {
+            error!("[Limiter:lib]get redis connection error:{}", err);
+            self.breaker.trip();
+            self.probe();
+        }
+
+        /// 按退避间隔探测redis，恢复后撤下第一版限流器，限流器被释放时退出
+        fn probe(&self)
+        {
+            let repo = match &self.redis {
+                Some(repo) => Arc::downgrade(repo),
+                None => return
+            };
+            if self.probing.swap(true, Ordering::SeqCst) {
+                return
+            }
+            let v1 = Arc::downgrade(&self.v1);
+            let breaker = Arc::downgrade(&self.breaker);
+            let probing = self.probing.clone();
+            let timeout = self.timeout;
+            tokio::spawn(async move {
+                let mut backoff = PROBE_MIN;
+                loop {
+                    tokio::time::sleep(Duration::from_millis(backoff)).await;
+                    let (repo, v1, breaker) = match (repo.upgrade(), v1.upgrade(), breaker.upgrade()) {
+                        (Some(repo), Some(v1), Some(breaker)) => (repo, v1, breaker),
+                        _ => break
+                    };
+                    // 已由半开试探恢复
+                    if breaker.state() == BreakerState::Closed {
+                        break
+                    }
+                    match timed(timeout, ping(&repo)).await {
+                        Ok(()) => {
+                            breaker.success();
+                            v1.lock().unwrap().take();
+                            info!("[Limiter:lib]redis recovered, switch back from v1 limiter");
+                            break
+                        }
+                        Err(err) => {
+                            backoff = (backoff * 2).min(PROBE_MAX);
+                            error!("[Limiter:lib]redis still unavailable:{}, next probe in {}ms", err, backoff);
+                        }
+                    }
+                }
+                probing.store(false, Ordering::SeqCst);
+            });
         }
     }
 
+    /// 按超时(ms)执行，0为不限
+    pub(crate) async fn timed<T>(timeout: u64, fut: impl Future<Output = redis::RedisResult<T>>) -> redis::RedisResult<T>
+    {
+        if timeout == 0 {
+            return fut.await
+        }
+        tokio::time::timeout(Duration::from_millis(timeout), fut)
+            .await
+            .unwrap_or_else(|_| Err((redis::ErrorKind::IoError, "redis访问超时").into()))
+    }
+
+    /// 探测redis是否可用
+    async fn ping(repo: &RedisRepo) -> redis::RedisResult<()>
+    {
+        let mut conn = repo.get_connection().await?;
+        redis::cmd("PING").query_async::<_, String>(&mut conn).await?;
+        Ok(())
+    }
+
+    /// 按自适应系数(千分比)换算限流次数
+    fn scale(limit: u64, factor: Option<u64>) -> u64
+    {
+        match factor {
+            Some(factor) => (limit as u128 * factor as u128 / 1000) as u64,
+            None => limit
+        }
+    }
+
+    /// 转义SCAN匹配模式中的特殊字符
+    fn glob_escape(str: &str) -> String
+    {
+        let mut escaped = String::with_capacity(str.len());
+        for c in str.chars() {
+            if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        escaped
+    }
+
     /// 从配置文本字符串获得config信息，该方法还可以校验config是否正确
     fn parse_config(str: String) -> Result<Config, Error>
     {
-        if str.eq("") {
+        if str.is_empty() {
             return Ok(Config::default())
         }
         let config = serde_json::from_str::<Config>(str.as_str())?;
+        if let Some(adaptive) = &config.adaptive {
+            adaptive.check()?;
+        }
         // let _ = config.store_to_db()?;
         Ok(config)
     }
@@ -294,11 +1032,21 @@ pub mod limiter {
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
-    use std::rc::Rc;
-    use std::cell::RefCell;
+    use std::sync::Arc;
     use crate::limiter::Limiter;
     use crate::statistician::report;
-    use crate::types::{Config, Ratio, Level};
+    use crate::cluster::slot;
+    use crate::redis::RedisRepo;
+    use crate::node::split_options;
+    use crate::breaker::Breaker;
+    use crate::call::{self, Call};
+    use crate::lua;
+    use crate::v1::V1;
+    use crate::limiter::{BreakerState, FailurePolicy, V1Config};
+    use crate::types::{Config, Ratio, Level, Mode, Algorithm, Quota, Period, Adaptive};
+    use chrono::{TimeZone, Utc};
+    use redis::{Cmd, ErrorKind, Pipeline, RedisFuture, Value};
+    use redis::aio::ConnectionLike;
     use tokio::runtime::Runtime;
 
     async fn get_limiter() -> Limiter {
@@ -320,22 +1068,26 @@ mod tests {
             let limiter = get_limiter().await;
             let bot = 1_i64;
             let limit = limiter.get_limit(bot, "whatever").unwrap();
-            let limiter = Rc::new(RefCell::new(limiter));
-            for _i in 0..30 {
-                match (*limiter.clone()).borrow_mut()
-                    .check(bot, "whatever1", limit.1.as_str(), limit.0)
-                    .await {
-                    Ok(r) => println!("{}", serde_json::to_string(&r).unwrap()),
-                    Err(err) => println!("{}", err)
-                }
-                match (*limiter.clone()).borrow_mut()
-                    .check(bot, "whatever2", limit.1.as_str(), limit.0)
-                    .await {
-                    Ok(r) => println!("{}", serde_json::to_string(&r).unwrap()),
-                    Err(err) => println!("{}", err)
-                }
+            let limiter = Arc::new(limiter);
+            let mut tasks = vec![];
+            for api in ["whatever1", "whatever2"] {
+                let limiter = limiter.clone();
+                let limit = limit.clone();
+                tasks.push(tokio::spawn(async move {
+                    for _i in 0..30 {
+                        match limiter
+                            .check(bot, api, limit.1.as_str(), limit.0)
+                            .await {
+                            Ok(r) => println!("{}", serde_json::to_string(&r).unwrap()),
+                            Err(err) => println!("{}", err)
+                        }
+                    }
+                }));
+            }
+            for task in tasks {
+                task.await.unwrap();
             }
-            let rep = (*limiter.clone()).borrow_mut().flush();
+            let rep = limiter.flush();
             println!("{}", report(vec![rep]));
         });
     }
@@ -351,11 +1103,14 @@ mod tests {
             level.insert(8_u64, vec![1_i64]);
             let config = Config {
                 ratio: Ratio::new(ratio),
-                level: Level::new(level)
+                level: Level::new(level, HashMap::new(), HashMap::new()),
+                mode: Mode::default(),
+                adaptive: None,
+                v1: None
             };
             let config = serde_json::to_string(&config).unwrap();
 
-            let mut limiter = get_limiter().await;
+            let limiter = get_limiter().await;
             limiter.stop();
             let _ = limiter.clear().await;
             match limiter.reset(config).await {
@@ -365,6 +1120,86 @@ mod tests {
         });
     }
 
+    #[test]
+    /// 独立的key前缀
+    fn namespace() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let limiter = get_limiter().await.set_namespace("limiter:test");
+            limiter.stop();
+            if let Err(err) = limiter.clear().await {
+                println!("{}", err)
+            }
+            if let Err(err) = limiter.clear_pattern("{1}:fixed:*").await {
+                println!("{}", err)
+            }
+        });
+    }
+
+    #[test]
+    /// 按bot和key重置
+    fn clear() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            assert!(Limiter::new(10).clear_bot(1).await.is_err());
+            assert!(Limiter::new(10).clear_key(1, "other").await.is_err());
+            // 运行中按bot和key重置
+            let limiter = get_limiter().await;
+            if let Err(err) = limiter.clear_key(1, "other").await {
+                println!("{}", err)
+            }
+            if let Err(err) = limiter.clear_bot(1).await {
+                println!("{}", err)
+            }
+        });
+    }
+
+    /// 按redis SCAN的规则匹配，支持*、?与\转义
+    fn glob(pattern: &[char], key: &[char]) -> bool
+    {
+        match pattern.split_first() {
+            None => key.is_empty(),
+            Some(('*', rest)) => (0..=key.len()).any(|i| glob(rest, &key[i..])),
+            Some(('?', rest)) => !key.is_empty() && glob(rest, &key[1..]),
+            Some(('\\', [c, rest @ ..])) | Some((c, rest)) => key.first() == Some(c) && glob(rest, &key[1..])
+        }
+    }
+
+    fn matches(pattern: &str, key: &str) -> bool
+    {
+        glob(&pattern.chars().collect::<Vec<_>>(), &key.chars().collect::<Vec<_>>())
+    }
+
+    #[test]
+    /// 清空时生成的匹配模式，不需要redis
+    fn clear_pattern()
+    {
+        let limiter = Limiter::new(10);
+        let pattern = limiter.namespace_pattern();
+        assert!(matches(&pattern, "limiter:bot_api:{1}:fixed:other"));
+        assert!(!matches(&pattern, "limiter:bot_api"));
+        assert!(!matches(&pattern, "limiter:bot_api:staging:{1}:fixed:other"));
+
+        let pattern = limiter.bot_pattern(1);
+        assert!(matches(&pattern, "limiter:bot_api:{1}:fixed:other"));
+        assert!(matches(&pattern, "limiter:bot_api:{1}:adaptive"));
+        assert!(!matches(&pattern, "limiter:bot_api:{12}:fixed:other"));
+
+        // key中的特殊字符按字面匹配
+        let patterns = limiter.key_patterns(1, "a*[b]?");
+        assert!(matches(&patterns[0], "limiter:bot_api:{1}:fixed:a*[b]?:60000"));
+        assert!(!matches(&patterns[0], "limiter:bot_api:{1}:fixed:axxbc:60000"));
+        assert!(matches(&patterns[1], "limiter:bot_api:{1}:quota:a*[b]?:202701"));
+        assert!(!matches(&patterns[1], "limiter:bot_api:{1}:quota:a*[b]?x:202701"));
+
+        let limiter = Limiter::new(10).set_namespace("app?");
+        assert!(matches(&limiter.bot_pattern(1), "app?:{1}:log:other"));
+        assert!(!matches(&limiter.bot_pattern(1), "apps:{1}:log:other"));
+        // 含有花括号的前缀不生效
+        let limiter = Limiter::new(10).set_namespace("app:{1}");
+        assert_eq!(limiter.namespace_pattern(), "limiter:bot_api:{*");
+    }
+
     #[test]
     /// 启用v1
     fn v1_test()
@@ -381,16 +1216,469 @@ mod tests {
                 ).await.run().await.unwrap();
             let bot = 1_i64;
             let limit = limiter.get_limit(bot, "whatever").unwrap();
-            let limiter = Rc::new(RefCell::new(limiter));
+            let limiter = Arc::new(limiter);
             for _i in 0..70 {
-                match (*limiter.clone()).borrow_mut()
+                match limiter
                     .check(bot, "whatever", limit.1.as_str(), limit.0).await {
                     Ok(r) => println!("{}", serde_json::to_string(&r).unwrap()),
                     Err(err) => println!("{}", err)
                 }
             }
-            let rep = (*limiter.clone()).borrow_mut().flush();
+            let rep = limiter.flush();
             println!("{}", report(vec![rep]));
         })
     }
-}
\ No newline at end of file
+
+    #[test]
+    /// v1令牌桶
+    fn v1_bucket()
+    {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut level = HashMap::new();
+            level.insert(10_u64, vec![1_i64]);
+            let mut window = HashMap::new();
+            window.insert(10_u64, 60_000_u64);
+            let mut mode = HashMap::new();
+            mode.insert(1_i64, Algorithm::TokenBucket { rate: 1, burst: 3 });
+            let config = Config {
+                ratio: Ratio::new(HashMap::new()),
+                level: Level::new(level, window, HashMap::new()),
+                mode: Mode::new(mode),
+                adaptive: None,
+                v1: None
+            };
+            let config = serde_json::to_string(&config).unwrap();
+
+            let limiter = Limiter::new(10).run().await.unwrap();
+            limiter.reset(config.clone()).await.unwrap();
+            let bot = 1_i64;
+            let limit = limiter.get_limit(bot, "whatever").unwrap();
+            let mut surplus = vec![];
+            for _i in 0..4 {
+                let r = limiter.check(bot, "whatever", limit.1.as_str(), limit.0).await.unwrap();
+                assert_eq!(r.total, 3);
+                surplus.push(r.surplus);
+            }
+            assert_eq!(surplus, vec![3, 2, 1, 0]);
+
+            // 两个节点时配置的burst同样均分
+            let limiter = Limiter::new(10).set_nodes(2).run().await.unwrap();
+            limiter.reset(config).await.unwrap();
+            let r = limiter.check(bot, "whatever", limit.1.as_str(), limit.0).await.unwrap();
+            assert_eq!((r.total, r.surplus), (2, 2));
+        })
+    }
+
+    #[test]
+    /// v1只在放行时计数
+    fn v1_cost()
+    {
+        let mut v1 = V1::new(V1Config::default());
+        let surplus: Vec<_> = [3, 3, 2, 1].into_iter()
+            .map(|cost| v1.check(1, "other", 5, 60_000, cost).surplus)
+            .collect();
+        assert_eq!(surplus, vec![5, 0, 2, 0]);
+    }
+
+    #[test]
+    /// v1定期清理已过期的计数
+    fn v1_evict()
+    {
+        let mut v1 = V1::new(V1Config::default());
+        v1.check(1, "short", 10, 100, 1);
+        v1.check(1, "long", 10, 60_000, 1);
+        v1.check_bucket(1, "bucket", 10, 10, 100, 1);
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        v1.check(2, "other", 10, 60_000, 1);
+        let mut keys: Vec<_> = v1.map.keys().map(|(_, key)| key.as_str()).collect();
+        keys.sort_unstable();
+        assert_eq!(keys, vec!["long", "other"]);
+        assert!(v1.buckets.is_empty());
+    }
+
+    #[test]
+    /// 附加窗口只能用于固定窗口
+    fn quota()
+    {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut level = HashMap::new();
+            level.insert(10_u64, vec![1_i64]);
+            let mut quota = HashMap::new();
+            quota.insert(10_u64, vec![
+                Quota { limit: 5000, window: 86_400_000, period: None, offset: 0 },
+                Quota { limit: 90000, window: 0, period: Some(Period::Month), offset: 480 }
+            ]);
+            let mut mode = HashMap::new();
+            mode.insert(1_i64, Algorithm::SlidingLog);
+            let mut config = Config {
+                ratio: Ratio::new(HashMap::new()),
+                level: Level::new(level.clone(), HashMap::new(), quota),
+                mode: Mode::new(mode),
+                adaptive: None,
+                v1: None
+            };
+
+            let limiter = Limiter::new(10).run().await.unwrap();
+            assert!(limiter.reset(serde_json::to_string(&config).unwrap()).await.is_err());
+            config.mode = Mode::new(HashMap::new());
+            assert!(limiter.reset(serde_json::to_string(&config).unwrap()).await.is_ok());
+            // offset超出一天
+            let mut quota = HashMap::new();
+            quota.insert(10_u64, vec![Quota { limit: 1, window: 0, period: Some(Period::Day), offset: -1440 }]);
+            config.level = Level::new(level, HashMap::new(), quota);
+            assert!(limiter.reset(serde_json::to_string(&config).unwrap()).await.is_err());
+
+            // 东八区跨年
+            let month = Quota { limit: 1, window: 0, period: Some(Period::Month), offset: 480 };
+            let now = Utc.with_ymd_and_hms(2026, 12, 31, 20, 0, 0).unwrap().timestamp_millis();
+            let end = Utc.with_ymd_and_hms(2027, 1, 31, 16, 0, 0).unwrap().timestamp_millis();
+            assert_eq!(month.period_end(now), Some(("202701".to_string(), end)));
+        })
+    }
+
+    #[test]
+    /// 并发限流
+    fn acquire()
+    {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let limiter = Limiter::new(10);
+            let permit = limiter.acquire(1, "whatever", "other", 2, 30_000).await.unwrap();
+            assert!(permit.is_some());
+            let limiter = limiter.run().await.unwrap();
+            assert!(limiter.acquire(1, "whatever", "other", 2, 30_000).await.is_err());
+        })
+    }
+
+    #[test]
+    /// 按接口配置的消耗计数
+    fn weighted()
+    {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut ratio = HashMap::new();
+            ratio.insert(1_i64, r#"{"send": {"limit": 6, "cost": 2}}"#.to_string());
+            let mut level = HashMap::new();
+            level.insert(10_u64, vec![1_i64]);
+            let mut window = HashMap::new();
+            window.insert(10_u64, 60_000_u64);
+            let mut mode = HashMap::new();
+            mode.insert(1_i64, Algorithm::TokenBucket { rate: 0, burst: 0 });
+            let mut config = Config {
+                ratio: Ratio::new(ratio),
+                level: Level::new(level, window, HashMap::new()),
+                mode: Mode::new(mode),
+                adaptive: None,
+                v1: None
+            };
+
+            let limiter = Limiter::new(10).run().await.unwrap();
+            limiter.reset(serde_json::to_string(&config).unwrap()).await.unwrap();
+            let limit = limiter.get_limit(1, "send").unwrap();
+            assert_eq!(limit.0, 6);
+            let mut surplus = vec![];
+            for _i in 0..4 {
+                let r = limiter.check(1, "send", limit.1.as_str(), limit.0).await.unwrap();
+                surplus.push(r.surplus);
+            }
+            assert_eq!(surplus, vec![6, 4, 2, 0]);
+            // 未配置的bot在v1中按调用方给出的限流次数，各key分别计数
+            let r = limiter.check_weighted(2, "other", "other", 4, 4).await.unwrap();
+            assert_eq!(r.surplus, 4);
+            let r = limiter.check_weighted(2, "other", "other", 4, 4).await.unwrap();
+            assert_eq!(r.surplus, 0);
+            let r = limiter.check_weighted(2, "send", "send", 4, 4).await.unwrap();
+            assert_eq!(r.surplus, 4);
+            // limit不是数值时报错而不是panic
+            let mut ratio = HashMap::new();
+            ratio.insert(1_i64, r#"{"send": {"limit": "6"}}"#.to_string());
+            config.ratio = Ratio::new(ratio);
+            assert!(limiter.reset(serde_json::to_string(&config).unwrap()).await.is_err());
+        })
+    }
+
+    #[test]
+    /// 自适应限流
+    fn adaptive()
+    {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let limiter = Limiter::new(10).run().await.unwrap();
+            assert_eq!(limiter.report_outcome(1, "whatever", false, 3000).await.unwrap(), 1000);
+
+            let mut config = Config::default();
+            config.adaptive = Some(Adaptive { min: 800, max: 100, increase: 10, decrease: 500, latency: 0 });
+            assert!(limiter.reset(serde_json::to_string(&config).unwrap()).await.is_err());
+            config.adaptive = Some(Adaptive { min: 100, max: 1000, increase: 10, decrease: 500, latency: 2000 });
+            limiter.reset(serde_json::to_string(&config).unwrap()).await.unwrap();
+            assert!(limiter.report_outcome(1, "whatever", false, 3000).await.is_err());
+        })
+    }
+
+    #[test]
+    /// 批量检测与逐个检测结果一致
+    fn batch()
+    {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let limiter = Arc::new(Limiter::new(10).run().await.unwrap());
+            let batch = limiter.clone();
+            let res = tokio::spawn(async move {
+                batch.check_batch(&[(1, "whatever", "other", 10), (2, "whatever", "other", 10)]).await
+            }).await.unwrap();
+            assert_eq!(res.len(), 2);
+            for (bot, r) in [3_i64, 4].into_iter().zip(res) {
+                let single = limiter.check(bot, "whatever", "other", 10).await.unwrap();
+                let r = r.unwrap();
+                assert_eq!((r.total, r.surplus), (single.total, single.surplus));
+            }
+            limiter.stop();
+            let res = limiter.check_batch(&[(1, "whatever", "other", 10)]).await;
+            let r = res[0].as_ref().unwrap();
+            assert_eq!((r.total, r.surplus), (10, 10));
+            let r = limiter.check(1, "whatever", "other", 10).await.unwrap();
+            assert_eq!((r.total, r.surplus), (10, 10));
+        })
+    }
+
+    /// 键名含bad的脚本调用返回错误，含denied的返回被第2个窗口拒绝，其余返回0
+    #[derive(Clone)]
+    struct Flaky;
+
+    impl ConnectionLike for Flaky {
+        fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value>
+        {
+            let packed = String::from_utf8_lossy(&cmd.get_packed_command()).to_string();
+            Box::pin(async move {
+                if packed.contains("bad") {
+                    Err((ErrorKind::ResponseError, "WRONGTYPE").into())
+                } else if packed.contains("denied") {
+                    Ok(Value::Bulk(vec![Value::Int(2), Value::Int(2), Value::Int(5)]))
+                } else {
+                    Ok(Value::Int(0))
+                }
+            })
+        }
+
+        fn req_packed_commands<'a>(&'a mut self, _: &'a Pipeline, _: usize, _: usize) -> RedisFuture<'a, Vec<Value>>
+        {
+            Box::pin(async { Ok(vec![]) })
+        }
+
+        fn get_db(&self) -> i64
+        {
+            0
+        }
+    }
+
+    #[test]
+    /// 批量检测中一项出错不影响其它项
+    fn batch_error()
+    {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let calls = ["ok", "bad", "ok"].iter()
+                .map(|key| Call::new(lua::FIXED_WINDOW).key(key.to_string()).decode(|u: u64| (10, u, 0, 1000)))
+                .collect();
+            let res = call::batch(&Flaky, calls, 0).await;
+            assert_eq!(res.iter().map(Result::is_ok).collect::<Vec<_>>(), vec![true, false, true]);
+            assert_eq!(res[0].as_ref().unwrap().surplus, 10);
+        })
+    }
+
+    #[test]
+    /// 多窗口被拒绝时给出拒绝的窗口序号
+    fn quota_index()
+    {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let windows = [(10_u64, 1000_u64), (5, 86_400_000)];
+            let call = Call::new(lua::COMPOUND)
+                .key("denied".to_string())
+                .decode_windows(move |(_, inx, used): (u64, u64, u64)| {
+                    let (limit, window) = windows[inx as usize - 1];
+                    ((limit, used, 0, window), inx, 0)
+                });
+            let r = call.invoke(&mut Flaky).await.unwrap();
+            assert_eq!((r.surplus, r.window, r.quota), (0, 86_400_000, 2));
+            let r = Call::new(lua::FIXED_WINDOW).key("ok".to_string()).decode(|u: u64| (10, u, 0, 1000)).invoke(&mut Flaky).await.unwrap();
+            assert_eq!((r.surplus, r.quota), (10, 0));
+        })
+    }
+
+    #[test]
+    /// 查询不消耗次数
+    fn peek()
+    {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            // 两个节点时本地按10/2限流
+            let limiter = Limiter::new(10).set_nodes(2).run().await.unwrap();
+            let r = limiter.peek(1, "other", 10).await.unwrap();
+            assert_eq!((r.total, r.surplus, r.reset), (5, 5, 0));
+            limiter.check(1, "whatever", "other", 10).await.unwrap();
+            for _i in 0..2 {
+                let r = limiter.peek(1, "other", 10).await.unwrap();
+                assert_eq!((r.total, r.surplus), (5, 4));
+                assert!(r.reset > 0 && r.reset <= 1000);
+            }
+        })
+    }
+
+    #[test]
+    /// 集群slot与hash tag
+    fn cluster_slot()
+    {
+        assert_eq!(slot(b"123456789"), 0x31C3);
+        assert_eq!(slot(b"foo"), 12182);
+        assert_eq!(slot(b"limiter:bot_api:{1}"), slot(b"limiter:bot_api:{1}:log:other"));
+        assert_ne!(slot(b"{}a"), slot(b"a"));
+    }
+
+    #[test]
+    /// 哨兵地址
+    fn sentinel_url()
+    {
+        assert!(RedisRepo::open("redis+sentinel://127.0.0.1:26379,127.0.0.2:26379/mymaster/2", "pwd").is_ok());
+        assert!(RedisRepo::open("redis+sentinel://:secret@127.0.0.1:26379/mymaster", "pwd").is_ok());
+        assert!(RedisRepo::open("redis+sentinel://127.0.0.1:26379", "pwd").is_err());
+        assert!(RedisRepo::open("redis+sentinel://127.0.0.1:26379/mymaster/x", "pwd").is_err());
+        assert!(RedisRepo::open("rediss+sentinel://127.0.0.1:26379/mymaster?username=limiter#insecure", "pwd").is_ok());
+    }
+
+    #[test]
+    /// TLS与ACL用户名的地址参数
+    fn tls_url()
+    {
+        let (url, options) = split_options("rediss://user@127.0.0.1:6380/1?ca=/tmp/ca.pem&username=limiter#insecure");
+        assert_eq!(url, "rediss://user@127.0.0.1:6380/1#insecure");
+        assert_eq!(options.get("ca").map(String::as_str), Some("/tmp/ca.pem"));
+        assert_eq!(options.get("username").map(String::as_str), Some("limiter"));
+
+        assert!(RedisRepo::open("rediss://user@127.0.0.1:6380/0", "pwd").is_ok());
+        assert!(RedisRepo::open("rediss://127.0.0.1:7000,rediss://127.0.0.1:7001", "pwd").is_ok());
+        // 单个种子节点的集群
+        assert!(RedisRepo::open("redis://127.0.0.1:7000?cluster=1", "pwd").is_ok());
+        assert!(RedisRepo::open("redis+unix:///tmp/redis.sock", "pwd").is_ok());
+        assert!(RedisRepo::open("redis+unix:///tmp/redis.sock?cluster=1", "pwd").is_err());
+        // 证书文件不存在、证书与私钥不成对
+        assert!(RedisRepo::open("rediss://127.0.0.1:6380/0?ca=/nonexistent/ca.pem", "pwd").is_err());
+        assert!(RedisRepo::open("rediss://127.0.0.1:6380/0?cert=/nonexistent/client.pem", "pwd").is_err());
+    }
+
+    #[test]
+    /// redis连不上时改用v1，后台探测期间保持v1
+    fn fallback()
+    {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let limiter = Limiter::new(10)
+                .set_repo("redis://127.0.0.1:1", "", &vec![], "", "")
+                .await.run().await.unwrap();
+            assert!(!limiter.is_fallback());
+            let r = limiter.check(1, "whatever", "other", 10).await.unwrap();
+            assert_eq!(r.total, 10);
+            assert!(r.error.is_some());
+            assert!(limiter.is_fallback());
+            assert_eq!(limiter.breaker(), BreakerState::Open);
+            tokio::time::sleep(std::time::Duration::from_millis(600)).await;
+            assert!(limiter.is_fallback());
+        })
+    }
+
+    #[test]
+    /// redis不可用时按失败策略给出结果
+    fn failure_policy()
+    {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            for policy in [FailurePolicy::Allow, FailurePolicy::Deny] {
+                let limiter = Limiter::new(10)
+                    .set_failure_policy(policy)
+                    .set_repo("redis://127.0.0.1:1", "", &vec![], "", "")
+                    .await.run().await.unwrap();
+                let r = limiter.check(1, "whatever", "other", 10).await.unwrap();
+                assert!(r.error.is_some());
+                assert_eq!(r.surplus > 0, policy == FailurePolicy::Allow);
+                assert_eq!(r.surplus == 0 && r.total > 0, policy == FailurePolicy::Deny);
+                assert!(!limiter.is_fallback());
+            }
+        })
+    }
+
+    #[test]
+    /// 调整第一版限流器
+    fn v1_limit()
+    {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let limiter = Limiter::new(10).run().await.unwrap();
+            assert_eq!(limiter.set_v1_limit(V1Config { nums: 2, wait: 500 }).wait, 200);
+            limiter.set_v1_limit(V1Config { nums: 2, wait: 0 });
+            let r = limiter.check(1, "whatever", "other", 10).await.unwrap();
+            assert_eq!(r.total, 2);
+            assert_eq!(limiter.get_v1_limit(), V1Config { nums: 2, wait: 0 });
+            // 配置中的设置在reset时生效
+            let mut config = Config::default();
+            config.v1 = Some(V1Config { nums: 3, wait: 0 });
+            limiter.reset(serde_json::to_string(&config).unwrap()).await.unwrap();
+            assert_eq!(limiter.get_v1_limit().nums, 3);
+            assert!(limiter.store_v1_limit(V1Config { nums: 0, wait: 0 }).await.is_ok());
+            assert_eq!(limiter.check(1, "whatever", "other", 10).await.unwrap().total, 10);
+        })
+    }
+
+    #[test]
+    /// 熔断器状态切换
+    fn breaker()
+    {
+        let breaker = Breaker::new(2, 50);
+        breaker.failure();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        breaker.failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(!breaker.allow());
+        std::thread::sleep(std::time::Duration::from_millis(60));
+        // 冷却后只放行一次试探
+        assert!(breaker.allow());
+        assert!(!breaker.allow());
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        breaker.failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+        std::thread::sleep(std::time::Duration::from_millis(60));
+        assert!(breaker.allow());
+        assert!(breaker.success());
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(!breaker.success());
+    }
+
+    #[test]
+    /// 可在多线程间共享
+    fn shareable()
+    {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Limiter>();
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let limiter = Arc::new(Limiter::new(1000).run().await.unwrap());
+            let mut tasks = vec![];
+            for bot in 0..8_i64 {
+                let limiter = limiter.clone();
+                tasks.push(tokio::spawn(async move {
+                    for _i in 0..10 {
+                        limiter.check(bot, "whatever", "other", 1000).await.unwrap();
+                    }
+                }));
+            }
+            for task in tasks {
+                task.await.unwrap();
+            }
+            let rep = limiter.flush();
+            assert_eq!(rep.len(), 8);
+            assert!(rep.values().all(|v| v.0 == 10));
+        })
+    }
+}
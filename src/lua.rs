@@ -0,0 +1,298 @@
+// 限流脚本
+
+/// 当前时间(ms)，instant为0时取redis服务器时间
+/// TIME是非确定命令，需先切换为按效果复制，否则之后的写命令会被拒绝(redis 5以下)
+macro_rules! clock {
+    () => {
+        r#"
+    local function clock(instant)
+        instant = tonumber(instant)
+        if(instant > 0)
+        then
+            return instant
+        end
+        redis.replicate_commands()
+        local time = redis.call('TIME')
+        return tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+    end
+"#
+    };
+}
+
+/// 读取固定窗口计数，返回{已使用次数, 新窗口的过期时间(ms)，已有窗口为0}
+/// 迁移期间计数key不存在且给出了初版hash时，读取其中field仍在1秒窗口内的计数作为新窗口的初值
+/// 滚动发布时旧节点仍在写初版hash，迁移结束后删除这段读取
+macro_rules! load_counter {
+    () => {
+        r#"
+    local function load(key, window, hash, field, instant)
+        local current = tonumber(redis.call('GET', key))
+        if(current)
+        then
+            return current, 0
+        end
+        if(hash)
+        then
+            local j_str = redis.call('HGET', hash, field)
+            if(j_str)
+            then
+                local json = cjson.decode(j_str)
+                if(clock(instant) - tonumber(json.instant) <= 1000)
+                then
+                    return tonumber(json.current), window
+                end
+            end
+        end
+        return 0, window
+    end
+"#
+    };
+}
+
+/// 固定窗口，每个bot:key一个计数key，在窗口结束时过期，返回本次之前窗口内已使用次数，拒绝时返回值不小于limit
+/// KEYS: 1.计数key 2.初版hash(可选) ARGV: 1.limit 2.window 3.cost 4.instant 5.初版field，后两个只在有初版hash时给出
+pub const FIXED_WINDOW: &str = concat!(clock!(), load_counter!(), r#"
+    local limit = tonumber(ARGV[1])
+    local cost = tonumber(ARGV[3])
+    local current, ttl = load(KEYS[1], tonumber(ARGV[2]), KEYS[2], ARGV[5], ARGV[4])
+    if(limit - current < cost)
+    then
+        return math.max(current, limit)
+    end
+    if(ttl > 0)
+    then
+        redis.call('SET', KEYS[1], current + cost, 'PX', ttl)
+    else
+        redis.call('INCRBY', KEYS[1], cost)
+    end
+    return current
+"#);
+
+/// 滑动窗口日志，每个bot:key一个zset，score为请求时间，返回本次之前窗口内已使用次数，拒绝时返回值不小于limit
+/// KEYS: 1.key ARGV: 1.limit 2.instant 3.window 4.cost
+pub const SLIDING_LOG: &str = concat!(clock!(), r#"
+    local limit = tonumber(ARGV[1])
+    local now = clock(ARGV[2])
+    local window = tonumber(ARGV[3])
+    local cost = tonumber(ARGV[4])
+    redis.call('ZREMRANGEBYSCORE', KEYS[1], 0, now - window)
+    local count = redis.call('ZCARD', KEYS[1])
+    if(limit - count < cost)
+    then
+        return math.max(count, limit)
+    end
+    for i = 0, cost - 1 do
+        redis.call('ZADD', KEYS[1], now, now .. '-' .. (count + i))
+    end
+    redis.call('PEXPIRE', KEYS[1], window)
+    return count
+"#);
+
+/// 令牌桶，每个bot:key一个hash，记录剩余令牌与上次补充时间，返回本次之前桶内已使用令牌数，拒绝时返回burst
+/// KEYS: 1.key ARGV: 1.rate 2.burst 3.instant 4.window 5.cost
+pub const TOKEN_BUCKET: &str = concat!(clock!(), r#"
+    local rate = tonumber(ARGV[1])
+    local burst = tonumber(ARGV[2])
+    local now = clock(ARGV[3])
+    local window = tonumber(ARGV[4])
+    local cost = tonumber(ARGV[5])
+    local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'instant')
+    local tokens = tonumber(bucket[1])
+    local instant = tonumber(bucket[2])
+    if(tokens == nil or instant == nil)
+    then
+        tokens = burst
+        instant = now
+    end
+    local ttl = window
+    if(rate > 0)
+    then
+        local elapsed = math.max(0, now - instant)
+        tokens = math.min(burst, tokens + elapsed * rate / window)
+        ttl = math.ceil(burst * window / rate)
+    end
+    local used = burst
+    if(tokens >= cost)
+    then
+        tokens = tokens - cost
+        used = burst - math.floor(tokens) - cost
+    end
+    redis.call('HMSET', KEYS[1], 'tokens', tokens, 'instant', now)
+    redis.call('PEXPIRE', KEYS[1], ttl)
+    return used
+"#);
+
+/// GCRA，每个bot:key只存一个理论到达时间(tat)，返回{本次之前已使用次数, 需等待的毫秒数}
+/// KEYS: 1.key ARGV: 1.limit 2.instant 3.window 4.cost
+pub const GCRA: &str = concat!(clock!(), r#"
+    local limit = tonumber(ARGV[1])
+    local now = clock(ARGV[2])
+    local window = tonumber(ARGV[3])
+    local cost = tonumber(ARGV[4])
+    if(limit <= 0 or cost > limit)
+    then
+        return {limit, window}
+    end
+    local interval = window / limit
+    local tat = tonumber(redis.call('GET', KEYS[1]))
+    if(tat == nil or tat < now)
+    then
+        tat = now
+    end
+    local diff = tat + interval * cost - now
+    if(diff - window > 0.000001)
+    then
+        return {limit, math.ceil(diff - window)}
+    end
+    redis.call('SET', KEYS[1], tat + interval * cost, 'PX', math.ceil(diff))
+    return {limit - math.floor((window - diff) / interval + 0.000001) - cost, 0}
+"#);
+
+/// 多窗口固定窗口，所有窗口都允许才计数，返回{拒绝的窗口序号(0为通过), 剩余最少的窗口序号, 该窗口本次之前已使用次数}
+/// KEYS: 依次为各窗口的计数key，之后为初版hash(可选)
+/// ARGV: 1.cost 2.窗口数n 之后每个窗口依次为 limit window expire_at，有初版hash时最后为 instant 初版field
+/// expire_at大于0的为日历窗口，计数key在expire_at(ms)过期，初版hash只用于第一个窗口
+pub const COMPOUND: &str = concat!(clock!(), load_counter!(), r#"
+    local cost = tonumber(ARGV[1])
+    local n = tonumber(ARGV[2])
+    local states = {}
+    local tightest = 1
+    local surplus = nil
+    for i = 1, n do
+        local limit = tonumber(ARGV[3 * i])
+        local hash = nil
+        if(i == 1)
+        then
+            hash = KEYS[n + 1]
+        end
+        local current, ttl = load(KEYS[i], tonumber(ARGV[3 * i + 1]), hash, ARGV[3 * n + 4], ARGV[3 * n + 3])
+        if(limit - current < cost)
+        then
+            return {i, i, math.max(current, limit)}
+        end
+        if(surplus == nil or limit - current < surplus)
+        then
+            surplus = limit - current
+            tightest = i
+        end
+        states[i] = {current, ttl}
+    end
+    for i = 1, n do
+        local expire_at = tonumber(ARGV[3 * i + 2])
+        if(states[i][2] > 0)
+        then
+            redis.call('SET', KEYS[i], states[i][1] + cost, 'PX', states[i][2])
+        else
+            redis.call('INCRBY', KEYS[i], cost)
+        end
+        if(expire_at > 0)
+        then
+            redis.call('PEXPIREAT', KEYS[i], expire_at)
+        end
+    end
+    return {0, tightest, states[tightest][1]}
+"#);
+
+/// 并发限流，每个bot:key一个zset，score为租约到期时间，返回占用中的名额数
+/// KEYS: 1.key ARGV: 1.limit 2.instant 3.lease 4.member
+pub const ACQUIRE: &str = concat!(clock!(), r#"
+    local now = clock(ARGV[2])
+    local lease = tonumber(ARGV[3])
+    redis.call('ZREMRANGEBYSCORE', KEYS[1], 0, now)
+    local count = redis.call('ZCARD', KEYS[1])
+    if(count < tonumber(ARGV[1]))
+    then
+        redis.call('ZADD', KEYS[1], now + lease, ARGV[4])
+        if(redis.call('PTTL', KEYS[1]) < lease)
+        then
+            redis.call('PEXPIRE', KEYS[1], lease)
+        end
+    end
+    return count
+"#);
+
+/// 自适应限流(AIMD)，成功且延迟正常时加法增大系数，否则乘法减小，系数为千分比，返回调整后的系数
+/// KEYS: 1.bot的hash ARGV: 1.field 2.success 3.latency 4.min 5.max 6.increase 7.decrease 8.threshold
+pub const ADAPT: &str = r#"
+    local min = tonumber(ARGV[4])
+    local max = tonumber(ARGV[5])
+    local threshold = tonumber(ARGV[8])
+    local factor = tonumber(redis.call('HGET', KEYS[1], ARGV[1])) or max
+    if(ARGV[2] == '1' and (threshold == 0 or tonumber(ARGV[3]) <= threshold))
+    then
+        factor = factor + tonumber(ARGV[6])
+    else
+        factor = math.floor(factor * tonumber(ARGV[7]) / 1000)
+    end
+    factor = math.max(min, math.min(max, factor))
+    redis.call('HSET', KEYS[1], ARGV[1], factor)
+    return factor
+"#;
+
+/// 只读查询，不消耗次数，返回{剩余最少的窗口序号, 已使用次数, 距重置的毫秒数}
+/// KEYS: 各窗口的计数key ARGV: 1.kind 2.instant 3.window 之后按kind依次为
+/// fixed: 窗口数n 各窗口的limit  log: 无  bucket: rate burst  gcra: limit
+pub const PEEK: &str = concat!(clock!(), r#"
+    local kind = ARGV[1]
+    local now = clock(ARGV[2])
+    local window = tonumber(ARGV[3])
+    if(kind == 'fixed')
+    then
+        local tightest, used, reset, surplus = 1, 0, 0, nil
+        for i = 1, tonumber(ARGV[4]) do
+            local limit = tonumber(ARGV[4 + i])
+            local current = tonumber(redis.call('GET', KEYS[i])) or 0
+            if(surplus == nil or limit - current < surplus)
+            then
+                surplus = limit - current
+                tightest = i
+                used = current
+                reset = math.max(0, redis.call('PTTL', KEYS[i]))
+            end
+        end
+        return {tightest, used, reset}
+    elseif(kind == 'log')
+    then
+        local from = '(' .. (now - window)
+        local count = redis.call('ZCOUNT', KEYS[1], from, '+inf')
+        local oldest = redis.call('ZRANGEBYSCORE', KEYS[1], from, '+inf', 'WITHSCORES', 'LIMIT', 0, 1)
+        local reset = 0
+        if(oldest[2])
+        then
+            reset = math.max(0, tonumber(oldest[2]) + window - now)
+        end
+        return {1, count, reset}
+    elseif(kind == 'bucket')
+    then
+        local rate = tonumber(ARGV[4])
+        local burst = tonumber(ARGV[5])
+        local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'instant')
+        local tokens = tonumber(bucket[1])
+        local instant = tonumber(bucket[2])
+        if(tokens == nil or instant == nil)
+        then
+            return {1, 0, 0}
+        end
+        local reset = math.max(0, redis.call('PTTL', KEYS[1]))
+        if(rate > 0)
+        then
+            tokens = math.min(burst, tokens + math.max(0, now - instant) * rate / window)
+            reset = math.ceil((burst - tokens) * window / rate)
+        end
+        return {1, burst - math.floor(tokens), reset}
+    else
+        local limit = tonumber(ARGV[4])
+        if(limit <= 0)
+        then
+            return {1, limit, window}
+        end
+        local tat = tonumber(redis.call('GET', KEYS[1]))
+        if(tat == nil or tat < now)
+        then
+            return {1, 0, 0}
+        end
+        local interval = window / limit
+        local diff = tat - now
+        return {1, math.max(0, limit - math.floor((window - diff) / interval + 0.000001)), math.ceil(diff)}
+    end
+"#);
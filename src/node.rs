@@ -0,0 +1,193 @@
+// redis节点连接，支持TLS、自定义CA和客户端证书
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use log::{error, info};
+use redis::{Client, Cmd, ConnectionAddr, ConnectionInfo, ErrorKind, Pipeline, RedisConnectionInfo, RedisFuture, RedisResult, Value};
+use redis::aio::{ConnectionLike, ConnectionManager, MultiplexedConnection};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio_native_tls::{native_tls, TlsConnector};
+
+/// 节点连接方式，由redis地址的scheme和查询参数决定，单节点、集群各节点和哨兵主节点共用
+/// rediss:// 开启TLS，地址末尾的 #insecure 跳过证书校验
+/// 查询参数 ca=CA证书 cert=客户端证书 key=客户端私钥(PKCS#8)，均为PEM文件路径，配置后即使用TLS
+#[derive(Clone, Default)]
+pub struct Connector {
+    tls: Option<Tls>
+}
+
+#[derive(Clone)]
+enum Tls {
+    Builtin(bool), //系统根证书，由redis自带的TLS连接，是否跳过校验
+    Custom(TlsConnector) //自定义CA或客户端证书
+}
+
+/// 单节点的连接
+#[derive(Clone)]
+pub enum NodeConnection {
+    Managed(ConnectionManager),
+    Tls(TlsConnection)
+}
+
+/// 自定义证书的TLS连接，可被多个任务clone共享，断开后下次使用时重连
+#[derive(Clone)]
+pub struct TlsConnection {
+    host: String,
+    port: u16,
+    info: RedisConnectionInfo,
+    connector: TlsConnector,
+    conn: Arc<Mutex<Option<MultiplexedConnection>>>
+}
+
+/// 把地址拆成redis可解析的部分和查询参数，#insecure 保留在地址中
+pub fn split_options(url: &str) -> (String, HashMap<String, String>)
+{
+    let (rest, fragment) = match url.split_once('#') {
+        Some((rest, fragment)) => (rest, format!("#{}", fragment)),
+        None => (url, String::new())
+    };
+    let (base, query) = rest.split_once('?').unwrap_or((rest, ""));
+    let options = query.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    (format!("{}{}", base, fragment), options)
+}
+
+impl Connector {
+    /// tls: 是否rediss地址, insecure: 是否跳过证书校验
+    pub fn new(tls: bool, insecure: bool, options: &HashMap<String, String>) -> RedisResult<Self>
+    {
+        let (ca, cert, key) = (options.get("ca"), options.get("cert"), options.get("key"));
+        if ca.is_none() && cert.is_none() && key.is_none() {
+            return Ok(Connector { tls: if tls { Some(Tls::Builtin(insecure)) } else { None } })
+        }
+        let mut builder = native_tls::TlsConnector::builder();
+        if let Some(ca) = ca {
+            builder.add_root_certificate(native_tls::Certificate::from_pem(&read(ca)?)?);
+        }
+        match (cert, key) {
+            (Some(cert), Some(key)) => {
+                builder.identity(native_tls::Identity::from_pkcs8(&read(cert)?, &read(key)?)?);
+            }
+            (None, None) => {}
+            _ => return Err((ErrorKind::InvalidClientConfig, "客户端证书和私钥需同时配置").into())
+        }
+        if insecure {
+            builder.danger_accept_invalid_certs(true).danger_accept_invalid_hostnames(true);
+        }
+        Ok(Connector { tls: Some(Tls::Custom(builder.build()?.into())) })
+    }
+
+    /// 按连接方式连接一个节点
+    pub async fn connect(&self, addr: ConnectionAddr, info: &RedisConnectionInfo) -> RedisResult<NodeConnection>
+    {
+        let addr = match (&self.tls, addr) {
+            (Some(Tls::Custom(connector)), ConnectionAddr::Tcp(host, port) | ConnectionAddr::TcpTls { host, port, .. }) => {
+                let conn = TlsConnection {
+                    host,
+                    port,
+                    info: info.clone(),
+                    connector: connector.clone(),
+                    conn: Arc::new(Mutex::new(None))
+                };
+                conn.get().await?;
+                return Ok(NodeConnection::Tls(conn))
+            }
+            (Some(Tls::Builtin(insecure)), ConnectionAddr::Tcp(host, port)) => ConnectionAddr::TcpTls { host, port, insecure: *insecure },
+            (_, addr) => addr
+        };
+        let client = Client::open(ConnectionInfo { addr, redis: info.clone() })?;
+        Ok(NodeConnection::Managed(ConnectionManager::new(client).await?))
+    }
+}
+
+impl TlsConnection {
+    /// 当前连接，没有时新建
+    async fn get(&self) -> RedisResult<MultiplexedConnection>
+    {
+        let mut conn = self.conn.lock().await;
+        if let Some(conn) = conn.as_ref() {
+            return Ok(conn.clone())
+        }
+        let tcp = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        let stream = self.connector.connect(&self.host, tcp).await
+            .inspect_err(|err| error!("[Limiter:tls]handshake with {}:{} error:{}", self.host, self.port, err))?;
+        let (multiplexed, driver) = MultiplexedConnection::new(&self.info, stream).await?;
+        tokio::spawn(driver);
+        info!("[Limiter:tls]connected to {}:{}", self.host, self.port);
+        *conn = Some(multiplexed.clone());
+        Ok(multiplexed)
+    }
+
+    /// 连接断开时丢弃，下次使用时重连
+    async fn check<T>(&self, result: RedisResult<T>) -> RedisResult<T>
+    {
+        if let Err(err) = &result {
+            if err.is_connection_dropped() || err.is_io_error() {
+                error!("[Limiter:tls]connection to {}:{} dropped:{}", self.host, self.port, err);
+                *self.conn.lock().await = None;
+            }
+        }
+        result
+    }
+}
+
+/// 读取证书文件
+fn read(path: &str) -> RedisResult<Vec<u8>>
+{
+    std::fs::read(path).map_err(|err| {
+        error!("[Limiter:tls]read {} error:{}", path, err);
+        err.into()
+    })
+}
+
+impl ConnectionLike for TlsConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value>
+    {
+        Box::pin(async move {
+            let result = self.get().await?.req_packed_command(cmd).await;
+            self.check(result).await
+        })
+    }
+
+    fn req_packed_commands<'a>(&'a mut self, cmd: &'a Pipeline, offset: usize, count: usize) -> RedisFuture<'a, Vec<Value>>
+    {
+        Box::pin(async move {
+            let result = self.get().await?.req_packed_commands(cmd, offset, count).await;
+            self.check(result).await
+        })
+    }
+
+    fn get_db(&self) -> i64
+    {
+        self.info.db
+    }
+}
+
+impl ConnectionLike for NodeConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value>
+    {
+        match self {
+            NodeConnection::Managed(conn) => conn.req_packed_command(cmd),
+            NodeConnection::Tls(conn) => conn.req_packed_command(cmd)
+        }
+    }
+
+    fn req_packed_commands<'a>(&'a mut self, cmd: &'a Pipeline, offset: usize, count: usize) -> RedisFuture<'a, Vec<Value>>
+    {
+        match self {
+            NodeConnection::Managed(conn) => conn.req_packed_commands(cmd, offset, count),
+            NodeConnection::Tls(conn) => conn.req_packed_commands(cmd, offset, count)
+        }
+    }
+
+    fn get_db(&self) -> i64
+    {
+        match self {
+            NodeConnection::Managed(conn) => conn.get_db(),
+            NodeConnection::Tls(conn) => conn.get_db()
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use log::error;
+use redis::AsyncCommands;
+use crate::types::Response;
+use crate::redis::RedisConnection;
+use crate::breaker::Breaker;
+use crate::limiter::timed;
+
+static SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// 归还名额所需的信息 (redis连接, zset key, member, 超时(ms), 熔断器)
+pub type Release = (RedisConnection, String, String, u64, Arc<Breaker>);
+
+/// 并发许可，drop时归还redis中占用的名额，归还同检测一样受超时限制并计入熔断器
+pub struct Permit {
+    response: Response,
+    release: Option<Release>
+}
+
+impl Permit {
+    pub fn new(response: Response, release: Option<Release>) -> Self
+    {
+        Permit {
+            response,
+            release
+        }
+    }
+
+    /// 生成名额的唯一标识
+    pub fn member(now: i64) -> String
+    {
+        format!("{}:{}:{}", std::process::id(), now, SEQ.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// 获取许可时的名额信息
+    pub fn response(&self) -> &Response
+    {
+        &self.response
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some((mut conn, key, member, timeout, breaker)) = self.release.take() {
+            // drop不能等待，交给当前runtime异步归还
+            match tokio::runtime::Handle::try_current() {
+                Ok(handle) => {
+                    handle.spawn(async move {
+                        match timed(timeout, conn.zrem::<_, _, u64>(key.as_str(), member.as_str())).await {
+                            Ok(_) => {
+                                breaker.success();
+                            }
+                            Err(err) => {
+                                // 释放失败时等待租约到期回收
+                                breaker.failure();
+                                error!("[Limiter:permit]release {} {} error:{}", key, member, err);
+                            }
+                        }
+                    });
+                }
+                Err(_) => error!("[Limiter:permit]release {} {} outside runtime, wait for lease", key, member)
+            }
+        }
+    }
+}
@@ -1,27 +1,227 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
 use log::error;
-use redis::{Client, RedisResult, ConnectionInfo, Connection};
+use redis::{Cmd, ConnectionAddr, ConnectionInfo, ErrorKind, Pipeline, RedisConnectionInfo, RedisFuture, RedisResult, Value};
+use redis::aio::ConnectionLike;
 use std::str::FromStr;
+use crate::cluster::ClusterConnection;
+use crate::sentinel::SentinelConnection;
+use crate::node::{split_options, Connector, NodeConnection};
 
-/// limiter的redis用单节点来处理
+/// 哨兵地址的scheme
+const SENTINEL_SCHEME: &str = "redis+sentinel://";
+/// 主节点使用TLS的哨兵地址的scheme
+const SENTINEL_TLS_SCHEME: &str = "rediss+sentinel://";
+
+/// limiter的redis支持单节点、集群和哨兵，地址中含逗号或带查询参数cluster=1时按集群处理，逗号分隔各种子节点
+/// 哨兵地址为 redis+sentinel://[:哨兵密码@]host1:port1,host2:port2/主节点名称[/db]，rediss+sentinel:// 时主节点使用TLS
+/// ACL用户名写在地址中 redis://用户名@host:port，或用查询参数 username=用户名，哨兵模式只能用后者
+/// TLS的CA和客户端证书用查询参数配置，如 rediss://user@host:6380/0?ca=/etc/ca.pem&cert=/etc/client.pem&key=/etc/client.key
+/// 所有检测共用一条多路复用的异步连接，连接断开后自动重连
 pub struct RedisRepo {
-    pub redis: Client
+    target: Target,
+    connector: Connector,
+    conn: Mutex<Option<RedisConnection>>
+}
+
+/// 连接目标
+enum Target {
+    Single(ConnectionInfo),
+    Cluster(Vec<String>, RedisConnectionInfo), //种子节点, 认证信息
+    Sentinel(Vec<ConnectionInfo>, String, RedisConnectionInfo) //哨兵, 主节点名称, 主节点认证信息
+}
+
+/// 单节点、集群或哨兵的连接
+#[derive(Clone)]
+pub enum RedisConnection {
+    Single(NodeConnection),
+    Cluster(ClusterConnection),
+    Sentinel(SentinelConnection)
 }
 
 impl RedisRepo {
     pub fn open(url: &str, pwd: &str) -> RedisResult<RedisRepo>
+    {
+        let (url, options) = split_options(url);
+        let repo = if url.starts_with(SENTINEL_SCHEME) || url.starts_with(SENTINEL_TLS_SCHEME) {
+            Self::open_sentinel(&url, pwd, &options)
+        } else if url.contains(',') || options.get("cluster").is_some_and(|c| c == "1" || c == "true") {
+            // 只配置一个种子节点的集群需用cluster=1指明
+            Self::open_cluster(&url, pwd, &options)
+        } else {
+            Self::open_single(&url, pwd, &options)
+        };
+        repo.inspect_err(|err| error!("[limiter:redis]open {} error:{}", url, err))
+    }
+
+    fn open_single(url: &str, pwd: &str, options: &HashMap<String, String>) -> RedisResult<RedisRepo>
     {
         let mut conn_info = ConnectionInfo::from_str(url)?;
-        conn_info.redis.password = Some(pwd.to_string());
-        match Client::open(conn_info) {
-            Ok(cli) => Ok(RedisRepo { redis: cli }),
-            Err(_e) => {
-                error!("[limiter:redis]open {} error", url);
-                Err(_e)
+        let (tls, insecure) = match conn_info.addr {
+            ConnectionAddr::TcpTls { insecure, .. } => (true, insecure),
+            _ => (false, false)
+        };
+        authorize(&mut conn_info.redis, pwd, options);
+        Ok(RedisRepo {
+            target: Target::Single(conn_info),
+            connector: Connector::new(tls, insecure, options)?,
+            conn: Mutex::new(None)
+        })
+    }
+
+    /// 集群模式，只记录种子节点，首次使用时再获取slot分布
+    fn open_cluster(urls: &str, pwd: &str, options: &HashMap<String, String>) -> RedisResult<RedisRepo>
+    {
+        let mut seeds = vec![];
+        let mut info = RedisConnectionInfo::default();
+        let (mut tls, mut insecure) = (false, false);
+        for url in urls.split(',').map(str::trim).filter(|u| !u.is_empty()) {
+            let conn_info = ConnectionInfo::from_str(url)?;
+            match conn_info.addr {
+                ConnectionAddr::Tcp(host, port) => seeds.push(format!("{}:{}", host, port)),
+                ConnectionAddr::TcpTls { host, port, insecure: skip } => {
+                    seeds.push(format!("{}:{}", host, port));
+                    tls = true;
+                    insecure = skip;
+                }
+                _ => return Err((ErrorKind::InvalidClientConfig, "集群节点只支持tcp地址").into())
+            }
+            info = conn_info.redis;
+        }
+        // 集群只有0号库
+        info.db = 0;
+        authorize(&mut info, pwd, options);
+        Ok(RedisRepo {
+            target: Target::Cluster(seeds, info),
+            connector: Connector::new(tls, insecure, options)?,
+            conn: Mutex::new(None)
+        })
+    }
+
+    /// 哨兵模式，只记录哨兵地址，首次使用时再询问主节点
+    fn open_sentinel(url: &str, pwd: &str, options: &HashMap<String, String>) -> RedisResult<RedisRepo>
+    {
+        let (tls, rest) = match url.strip_prefix(SENTINEL_TLS_SCHEME) {
+            Some(rest) => (true, rest),
+            None => (false, &url[SENTINEL_SCHEME.len()..])
+        };
+        let (rest, insecure) = match rest.strip_suffix("#insecure") {
+            Some(rest) => (rest, true),
+            None => (rest, false)
+        };
+        let (auth, rest) = match rest.rsplit_once('@') {
+            Some((auth, rest)) => (format!("{}@", auth), rest),
+            None => (String::new(), rest)
+        };
+        let (hosts, path) = rest.split_once('/').unwrap_or((rest, ""));
+        let mut path = path.split('/').filter(|p| !p.is_empty());
+        let master = match path.next() {
+            Some(master) => master.to_string(),
+            None => return Err((ErrorKind::InvalidClientConfig, "哨兵地址缺少主节点名称").into())
+        };
+        let db = match path.next().map(str::parse::<i64>) {
+            Some(Ok(db)) => db,
+            Some(Err(_)) => return Err((ErrorKind::InvalidClientConfig, "哨兵地址的db不正确").into()),
+            None => 0
+        };
+        let mut sentinels = vec![];
+        for host in hosts.split(',').map(str::trim).filter(|h| !h.is_empty()) {
+            sentinels.push(ConnectionInfo::from_str(format!("redis://{}{}", auth, host).as_str())?);
+        }
+        let mut info = RedisConnectionInfo { db, ..Default::default() };
+        authorize(&mut info, pwd, options);
+        Ok(RedisRepo {
+            target: Target::Sentinel(sentinels, master, info),
+            connector: Connector::new(tls, insecure, options)?,
+            conn: Mutex::new(None)
+        })
+    }
+
+    /// 是否集群模式
+    pub fn is_cluster(&self) -> bool
+    {
+        matches!(self.target, Target::Cluster(..))
+    }
+
+    /// 获取共享的异步连接，首次使用或之前建立失败时才新建
+    pub async fn get_connection(&self) -> RedisResult<RedisConnection> {
+        if let Some(conn) = self.conn.lock().unwrap().clone() {
+            return Ok(conn)
+        }
+        let conn = match &self.target {
+            Target::Single(conn_info) => RedisConnection::Single(self.connector.connect(conn_info.addr.clone(), &conn_info.redis).await?),
+            Target::Cluster(seeds, info) => RedisConnection::Cluster(
+                ClusterConnection::connect(seeds.clone(), info.clone(), self.connector.clone()).await?),
+            Target::Sentinel(sentinels, master, info) => RedisConnection::Sentinel(
+                SentinelConnection::connect(sentinels.clone(), master.clone(), info.clone(), self.connector.clone()).await?)
+        };
+        *self.conn.lock().unwrap() = Some(conn.clone());
+        Ok(conn)
+    }
+
+    /// 找出匹配pattern的所有key，集群时逐个主节点扫描
+    pub async fn scan(&self, pattern: &str) -> RedisResult<Vec<String>> {
+        let conns = match self.get_connection().await? {
+            RedisConnection::Cluster(cluster) => cluster.masters().await?.into_iter().map(RedisConnection::Single).collect(),
+            conn => vec![conn]
+        };
+        let mut keys = vec![];
+        for mut conn in conns {
+            let mut cursor = 0_u64;
+            loop {
+                let (next, batch) = redis::cmd("SCAN")
+                    .arg(cursor)
+                    .arg("MATCH")
+                    .arg(pattern)
+                    .arg("COUNT")
+                    .arg(1000)
+                    .query_async::<_, (u64, Vec<String>)>(&mut conn)
+                    .await?;
+                keys.extend(batch);
+                if next == 0 {
+                    break
+                }
+                cursor = next;
             }
         }
+        Ok(keys)
+    }
+}
+
+/// 填入密码，查询参数中的username优先于地址中的用户名
+fn authorize(info: &mut RedisConnectionInfo, pwd: &str, options: &HashMap<String, String>)
+{
+    info.password = Some(pwd.to_string());
+    if let Some(username) = options.get("username") {
+        info.username = Some(username.clone());
+    }
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value>
+    {
+        match self {
+            RedisConnection::Single(conn) => conn.req_packed_command(cmd),
+            RedisConnection::Cluster(conn) => conn.req_packed_command(cmd),
+            RedisConnection::Sentinel(conn) => conn.req_packed_command(cmd)
+        }
+    }
+
+    fn req_packed_commands<'a>(&'a mut self, cmd: &'a Pipeline, offset: usize, count: usize) -> RedisFuture<'a, Vec<Value>>
+    {
+        match self {
+            RedisConnection::Single(conn) => conn.req_packed_commands(cmd, offset, count),
+            RedisConnection::Cluster(conn) => conn.req_packed_commands(cmd, offset, count),
+            RedisConnection::Sentinel(conn) => conn.req_packed_commands(cmd, offset, count)
+        }
     }
 
-    pub fn get_connection(&self) -> RedisResult<Connection> {
-        self.redis.get_connection()
+    fn get_db(&self) -> i64
+    {
+        match self {
+            RedisConnection::Single(conn) => conn.get_db(),
+            RedisConnection::Cluster(conn) => conn.get_db(),
+            RedisConnection::Sentinel(conn) => conn.get_db()
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,132 @@
+// redis哨兵主节点发现
+
+use std::sync::{Arc, RwLock};
+use log::{error, info};
+use redis::{Client, Cmd, ConnectionAddr, ConnectionInfo, ErrorKind, Pipeline, RedisConnectionInfo, RedisError, RedisFuture, RedisResult, Value};
+use redis::aio::ConnectionLike;
+use crate::node::{Connector, NodeConnection};
+
+/// 哨兵连接，通过哨兵找到当前主节点，可被多个任务clone共享
+/// 主节点不可达或已降为从节点(READONLY)时重新询问哨兵，主节点切换后重试一次
+#[derive(Clone)]
+pub struct SentinelConnection {
+    sentinels: Vec<ConnectionInfo>, //哨兵地址
+    master: String, //主节点名称
+    info: RedisConnectionInfo, //主节点的认证信息
+    connector: Connector, //主节点的连接方式
+    current: Arc<RwLock<Option<(String, NodeConnection)>>>, //(当前主节点地址, 连接)
+}
+
+impl SentinelConnection {
+    /// 通过哨兵连接到主节点
+    pub async fn connect(sentinels: Vec<ConnectionInfo>, master: String, info: RedisConnectionInfo, connector: Connector) -> RedisResult<Self>
+    {
+        let conn = SentinelConnection {
+            sentinels,
+            master,
+            info,
+            connector,
+            current: Arc::new(RwLock::new(None)),
+        };
+        conn.resolve().await?;
+        Ok(conn)
+    }
+
+    /// 依次询问哨兵当前主节点并建立连接，返回主节点地址
+    async fn resolve(&self) -> RedisResult<String>
+    {
+        let mut last = None;
+        for sentinel in self.sentinels.iter() {
+            match self.ask(sentinel).await {
+                Ok((addr, conn)) => {
+                    info!("[Limiter:sentinel]master {} is {}", self.master, addr);
+                    *self.current.write().unwrap() = Some((addr.clone(), conn));
+                    return Ok(addr)
+                }
+                Err(err) => {
+                    error!("[Limiter:sentinel]resolve {} from {:?} error:{}", self.master, sentinel.addr, err);
+                    last = Some(err)
+                }
+            }
+        }
+        Err(last.unwrap_or_else(|| (ErrorKind::MasterDown, "没有可用的哨兵").into()))
+    }
+
+    /// 向一个哨兵询问主节点，并确认其角色
+    async fn ask(&self, sentinel: &ConnectionInfo) -> RedisResult<(String, NodeConnection)>
+    {
+        let mut conn = Client::open(sentinel.clone())?.get_async_connection().await?;
+        let (host, port) = redis::cmd("SENTINEL")
+            .arg("get-master-addr-by-name")
+            .arg(self.master.as_str())
+            .query_async::<_, Option<(String, u16)>>(&mut conn)
+            .await?
+            .ok_or_else(|| RedisError::from((ErrorKind::MasterDown, "哨兵未找到主节点")))?;
+        let addr = format!("{}:{}", host, port);
+        if let Some((current, conn)) = self.current.read().unwrap().clone() {
+            if current == addr {
+                return Ok((addr, conn))
+            }
+        }
+        let mut conn = self.connector.connect(ConnectionAddr::Tcp(host, port), &self.info).await?;
+        // 哨兵的信息可能滞后，确认是主节点
+        let role = redis::cmd("ROLE").query_async::<_, Vec<Value>>(&mut conn).await?;
+        match role.first() {
+            Some(Value::Data(role)) if role.as_slice() == b"master" => Ok((addr, conn)),
+            _ => Err((ErrorKind::MasterDown, "哨兵返回的节点不是主节点", addr).into())
+        }
+    }
+
+    /// 当前主节点的连接
+    async fn master(&self) -> RedisResult<(String, NodeConnection)>
+    {
+        if let Some(current) = self.current.read().unwrap().clone() {
+            return Ok(current)
+        }
+        self.resolve().await?;
+        self.current.read().unwrap().clone().ok_or_else(|| (ErrorKind::MasterDown, "未找到主节点").into())
+    }
+
+    /// 主节点出错时重新解析，主节点已切换返回true
+    async fn failover(&self, addr: &str, err: &RedisError) -> bool
+    {
+        if !(err.code() == Some("READONLY") || err.kind() == ErrorKind::MasterDown
+            || err.is_connection_dropped() || err.is_connection_refusal() || err.is_io_error()) {
+            return false
+        }
+        error!("[Limiter:sentinel]master {} error:{}", addr, err);
+        match self.resolve().await {
+            Ok(current) => current != addr,
+            Err(_) => false
+        }
+    }
+}
+
+impl ConnectionLike for SentinelConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value>
+    {
+        Box::pin(async move {
+            let (addr, mut conn) = self.master().await?;
+            match conn.req_packed_command(cmd).await {
+                Err(err) if self.failover(&addr, &err).await => self.master().await?.1.req_packed_command(cmd).await,
+                result => result
+            }
+        })
+    }
+
+    fn req_packed_commands<'a>(&'a mut self, cmd: &'a Pipeline, offset: usize, count: usize) -> RedisFuture<'a, Vec<Value>>
+    {
+        Box::pin(async move {
+            let (addr, mut conn) = self.master().await?;
+            match conn.req_packed_commands(cmd, offset, count).await {
+                Err(err) if self.failover(&addr, &err).await => self.master().await?.1.req_packed_commands(cmd, offset, count).await,
+                result => result
+            }
+        })
+    }
+
+    fn get_db(&self) -> i64
+    {
+        self.info.db
+    }
+}
@@ -3,24 +3,48 @@
 use std::collections::HashMap;
 use std::io::{Error, ErrorKind};
 use log::error;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use crate::types::Level;
+use crate::types::{Algorithm, Level, Mode, Quota};
 use crate::types::Ratio;
 use crate::limiter::Strategies;
 
+/// 默认窗口长度(ms)
+pub const WINDOW: u64 = 1000;
+
 /// 生成策略信息
-pub fn generate(lev: &Level, rat: &Ratio) -> Result<Strategies, Error>
+pub fn generate(lev: &Level, rat: &Ratio, mode: &Mode) -> Result<Strategies, Error>
 {
     let mut strategies = HashMap::new();
     let ratios = rat.map();
+    let modes = mode.map();
+    let windows = lev.window();
+    let quotas = lev.quota();
     for (total, bots) in lev.map() {
+        let window = windows.get(&total).copied().unwrap_or(WINDOW);
+        let quota = quotas.get(&total).cloned().unwrap_or_default();
+        if window == 0 || quota.iter().any(|q| q.window == 0 && q.period.is_none()) {
+            error!("[Limiter:strategy.rs] window of level {} is 0", total);
+            return Err(Error::new(ErrorKind::InvalidData, "level窗口长度不能为0"));
+        }
+        if quota.iter().any(|q| q.offset.abs() >= 1440) {
+            error!("[Limiter:strategy.rs] offset of level {} is out of range", total);
+            return Err(Error::new(ErrorKind::InvalidData, "附加窗口offset须在(-1440, 1440)分钟内"));
+        }
         for bot in bots {
             let ratio = if ratios.contains_key(&bot) {
                 ratios.get(&bot).unwrap().to_string()
             } else {
                 "".to_string()
             };
-            let strategy = generate_strategy_by_bot(total, ratio)?;
+            let algorithm = modes.get(&bot).copied().unwrap_or_default();
+            if !quota.is_empty() && algorithm != Algorithm::FixedWindow {
+                error!("[Limiter:strategy.rs] bot {} uses quota with {:?}", bot, algorithm);
+                return Err(Error::new(ErrorKind::InvalidData, "附加窗口只支持固定窗口算法"));
+            }
+            let mut strategy = Strategy::new(total, algorithm, window);
+            strategy.quotas = quota.clone();
+            let strategy = generate_strategy_by_bot(total, ratio, strategy)?;
             strategies.insert(bot, strategy);
         }
     }
@@ -28,10 +52,10 @@ pub fn generate(lev: &Level, rat: &Ratio) -> Result<Strategies, Error>
 }
 
 /// 根据bot_id、total等信息生成每个bot的策略
-fn generate_strategy_by_bot(total: u64, ratio: String) -> Result<String, Error>
+fn generate_strategy_by_bot(total: u64, ratio: String, mut base: Strategy) -> Result<String, Error>
 {
-    if ratio.eq("") {
-        return Ok(Strategy::Default(total).to_str())
+    if ratio.is_empty() {
+        return Ok(base.to_str())
     }
     let val = match serde_json::from_str::<Value>(&ratio) {
         Ok(v) => v,
@@ -44,11 +68,36 @@ fn generate_strategy_by_bot(total: u64, ratio: String) -> Result<String, Error>
     if let Some(object) = val.as_object() {
         let mut strategy = HashMap::new();
         for (api, num) in object {
+            // 对象型可额外设置每次调用的消耗 {"limit": 数值, "cost": 整数}
+            let num = if let Some(item) = num.as_object() {
+                if let Some(cost) = item.get("cost") {
+                    let cost = match cost.as_u64() {
+                        Some(c) => c,
+                        None => {
+                            error!("[Limiter:strategy.rs] cost of {} is not an integer", api);
+                            return Err(Error::new(ErrorKind::InvalidData, "ratio内容不正确，cost须为整数"));
+                        }
+                    };
+                    base.costs.insert(api.clone(), cost);
+                }
+                match item.get("limit") {
+                    Some(n) => n,
+                    None => continue
+                }
+            } else {
+                num
+            };
             // 两种情形，整数型 + 小数型
             let num = if num.is_f64() {
                 (num.as_f64().unwrap() * (total as f64)) as u64
             } else {
-                num.as_u64().unwrap()
+                match num.as_u64() {
+                    Some(n) => n,
+                    None => {
+                        error!("[Limiter:strategy.rs] limit of {} is not a number", api);
+                        return Err(Error::new(ErrorKind::InvalidData, "ratio内容不正确，limit须为数值"));
+                    }
+                }
             };
             sum += num;
             if sum > total {
@@ -58,63 +107,119 @@ fn generate_strategy_by_bot(total: u64, ratio: String) -> Result<String, Error>
             strategy.insert(api.clone(), num);
         }
         strategy.insert("other".to_string(), total - sum);
+        base.limits = strategy;
 
-        Ok(Strategy::Map(strategy).to_str())
+        Ok(base.to_str())
     } else {
-        Ok(Strategy::Default(total).to_str())
+        Ok(base.to_str())
     }
 }
 
 /// 获得具体限流次数
 pub fn limit(map: &Strategies, bot: i64, key: String) -> Result<(u64, String), Error>
+{
+    if let Some(strategy) = parse(map, bot)? {
+        if let Some(num) = strategy.limits.get(key.as_str()) {
+            Ok((*num, key))
+        } else {
+            let num = strategy.limits.get("other").copied().unwrap_or(0);
+            Ok((num, "other".to_string()))
+        }
+    } else {
+        Ok((0, "other".to_string()))
+    }
+}
+
+/// 获得bot的策略，未配置的bot返回默认策略
+pub fn find(map: &Strategies, bot: i64) -> Result<Strategy, Error>
+{
+    Ok(parse(map, bot)?.unwrap_or_else(|| Strategy::new(0, Algorithm::default(), WINDOW)))
+}
+
+/// 令牌桶参数(rate, burst)，未设置的取限流次数
+pub fn bucket(limit: u64, rate: u64, burst: u64) -> (u64, u64)
+{
+    let rate = if rate == 0 { limit } else { rate };
+    let burst = if burst == 0 { rate } else { burst };
+    (rate, burst)
+}
+
+/// 解析bot的策略
+fn parse(map: &Strategies, bot: i64) -> Result<Option<Strategy>, Error>
 {
     if let Some(val) = map.get(&bot) {
-        let data = match serde_json::from_str::<Value>(val) {
-            Ok(v) => v,
+        match serde_json::from_str::<Strategy>(val) {
+            Ok(s) => Ok(Some(s)),
             Err(err) => {
                 error!("[Limiter:strategy]parse strategy error:{}", err);
-                return Err(Error::new(ErrorKind::InvalidData, "解析策略数据失败"))
-            }
-        };
-        if let Some(object) = data.as_object() {
-            if object.contains_key(key.as_str()) {
-                let num = object.get(key.as_str()).unwrap();
-                Ok((num.as_u64().unwrap(), key.to_string()))
-            } else {
-                let num = object.get("other").unwrap();
-                Ok((num.as_u64().unwrap(), "other".to_string()))
+                Err(Error::new(ErrorKind::InvalidData, "解析策略数据失败"))
             }
-        } else {
-            Ok((0, "other".to_string()))
         }
     } else {
-        Ok((0, "other".to_string()))
+        Ok(None)
     }
 }
 
 /// 生成string内容
-enum Strategy {
-    // 默认值
-    Default(u64),
-    // 设定值
-    Map(HashMap<String, u64>)
+#[derive(Serialize, Deserialize)]
+pub struct Strategy {
+    // 各接口限流次数，未配置的接口共用other
+    limits: HashMap<String, u64>,
+    // 限流算法
+    #[serde(default)]
+    pub algorithm: Algorithm,
+    // 窗口长度(ms)
+    #[serde(default = "default_window")]
+    pub window: u64,
+    // 级别总次数
+    #[serde(default)]
+    total: u64,
+    // 附加窗口
+    #[serde(default)]
+    quotas: Vec<Quota>,
+    // 各接口每次调用的默认消耗，未配置的为1
+    #[serde(default)]
+    costs: HashMap<String, u64>
+}
+
+fn default_window() -> u64 {
+    WINDOW
 }
 
 impl Strategy {
-    fn to_str(&self) -> String {
-        match self {
-            Strategy::Default(u) => {
-                let mut val = Value::Null;
-                val["other"] = Value::from(*u);
-                val.to_string()
-            },
-            Strategy::Map(map) => {
-                let mut val = Value::Null;
-                for item in map {
-                    val[item.0.as_str()] = Value::from(*item.1);
-                }
-                val.to_string()
-            }
+    fn new(total: u64, algorithm: Algorithm, window: u64) -> Self {
+        let mut limits = HashMap::new();
+        limits.insert("other".to_string(), total);
+        Strategy {
+            limits,
+            algorithm,
+            window,
+            total,
+            quotas: vec![],
+            costs: HashMap::new()
         }
     }
-}
\ No newline at end of file
+
+    /// 接口每次调用的默认消耗
+    pub fn cost(&self, api: &str) -> u64 {
+        self.costs.get(api).copied().unwrap_or(1)
+    }
+
+    /// 按接口限流次数占总次数的比例换算附加窗口
+    pub fn quotas(&self, limit: u64) -> Vec<Quota> {
+        self.quotas.iter()
+            .map(|q| {
+                let limit = if self.total == 0 {
+                    q.limit
+                } else {
+                    (q.limit as u128 * limit as u128 / self.total as u128) as u64
+                };
+                Quota { limit, ..*q }
+            })
+            .collect()
+    }
+
+    fn to_str(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
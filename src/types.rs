@@ -1,6 +1,8 @@
 use std::collections::HashMap;
+use chrono::{Datelike, FixedOffset, NaiveDate, TimeZone};
 use serde::{Deserialize, Serialize};
-use std::io::Error;
+use log::error;
+use std::io::{Error, ErrorKind};
 use crate::limiter::Strategies;
 use crate::strategy;
 
@@ -8,7 +10,13 @@ use crate::strategy;
 #[derive(Deserialize, Serialize)]
 pub struct Config {
     pub ratio: Ratio,
-    pub level: Level
+    pub level: Level,
+    #[serde(default)]
+    pub mode: Mode,
+    #[serde(default)]
+    pub adaptive: Option<Adaptive>,
+    #[serde(default)]
+    pub v1: Option<V1Config>
 }
 
 impl Config {
@@ -20,14 +28,17 @@ impl Config {
     {
         Config {
             ratio: Ratio::new(HashMap::new()),
-            level: Level::new(HashMap::new())
+            level: Level::new(HashMap::new(), HashMap::new(), HashMap::new()),
+            mode: Mode::new(HashMap::new()),
+            adaptive: None,
+            v1: None
         }
     }
 
     /// 得到strategies
     pub fn get_strategies(&self) -> Result<Strategies, Error>
     {
-        strategy::generate(&self.level, &self.ratio)
+        strategy::generate(&self.level, &self.ratio, &self.mode)
     }
 }
 
@@ -54,12 +65,18 @@ impl Ratio {
 #[derive(Deserialize, Serialize)]
 pub struct Level {
     map: HashMap<u64, Vec<i64>>,
+    #[serde(default)]
+    window: HashMap<u64, u64>, //各级别的窗口长度(ms)，未设置的为1000
+    #[serde(default)]
+    quota: HashMap<u64, Vec<Quota>>, //各级别的附加窗口，需与主窗口同时满足
 }
 
 impl Level {
-    pub fn new(map: HashMap<u64, Vec<i64>>) -> Self {
+    pub fn new(map: HashMap<u64, Vec<i64>>, window: HashMap<u64, u64>, quota: HashMap<u64, Vec<Quota>>) -> Self {
         Level {
-            map
+            map,
+            window,
+            quota
         }
     }
 
@@ -67,20 +84,176 @@ impl Level {
     {
         self.map.clone()
     }
+
+    pub fn window(&self) -> HashMap<u64, u64>
+    {
+        self.window.clone()
+    }
+
+    pub fn quota(&self) -> HashMap<u64, Vec<Quota>>
+    {
+        self.quota.clone()
+    }
+}
+
+/// 附加窗口，limit为整个bot在window(ms)内的次数，各接口按配比分摊
+/// 设置period时为日历窗口，按offset时区在每天零点或每月1日零点重置，忽略window
+/// offset是固定的UTC偏移而非时区，不随夏令时变化，实行夏令时的地区有半年会在当地零点前后一小时重置
+#[derive(Deserialize, Serialize, Clone, Copy, Debug)]
+pub struct Quota {
+    pub limit: u64,
+    #[serde(default)]
+    pub window: u64,
+    #[serde(default)]
+    pub period: Option<Period>,
+    #[serde(default)]
+    pub offset: i32 //时区相对UTC的分钟数，如东八区为480
+}
+
+impl Quota {
+    /// 日历窗口当前周期的标识与结束时间(ms)，非日历窗口返回None
+    pub fn period_end(&self, now: i64) -> Option<(String, i64)>
+    {
+        let period = self.period?;
+        let tz = FixedOffset::east_opt(self.offset * 60)?;
+        let date = tz.timestamp_millis_opt(now).single()?.date_naive();
+        let (id, next) = match period {
+            Period::Day => (date.format("%Y%m%d").to_string(), date.succ_opt()?),
+            Period::Month => {
+                let next = if date.month() == 12 {
+                    NaiveDate::from_ymd_opt(date.year() + 1, 1, 1)?
+                } else {
+                    NaiveDate::from_ymd_opt(date.year(), date.month() + 1, 1)?
+                };
+                (date.format("%Y%m").to_string(), next)
+            }
+        };
+        let end = tz.from_local_datetime(&next.and_hms_opt(0, 0, 0)?).single()?;
+        Some((id, end.timestamp_millis()))
+    }
+}
+
+/// 日历周期
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Period {
+    Day,
+    Month
+}
+
+/// 算法设置，未设置的bot使用固定窗口
+#[derive(Deserialize, Serialize, Default)]
+pub struct Mode {
+    map: HashMap<i64, Algorithm>
+}
+
+impl Mode {
+    pub fn new(map: HashMap<i64, Algorithm>) -> Self {
+        Mode {
+            map
+        }
+    }
+
+    pub fn map(&self) -> HashMap<i64, Algorithm>
+    {
+        self.map.clone()
+    }
+}
+
+/// 限流算法
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Algorithm {
+    // 固定窗口
+    #[default]
+    FixedWindow,
+    // 滑动窗口日志
+    SlidingLog,
+    // 令牌桶 rate为每个窗口补充的令牌数，burst为桶容量，为0时都取接口限流次数
+    TokenBucket {
+        #[serde(default)]
+        rate: u64,
+        #[serde(default)]
+        burst: u64
+    },
+    // 通用信元速率算法，只存理论到达时间，可给出准确的等待时间
+    Gcra
+}
+
+/// 自适应限流设置，系数均为千分比，实际限流次数 = 策略限流次数 * 系数 / 1000
+#[derive(Deserialize, Serialize, Clone, Copy, Debug)]
+pub struct Adaptive {
+    pub min: u64, //系数下限
+    pub max: u64, //系数上限
+    pub increase: u64, //健康时每次增加的系数
+    pub decrease: u64, //异常时系数乘以decrease/1000
+    #[serde(default)]
+    pub latency: u64 //超过该延迟(ms)视为异常，0表示不看延迟
+}
+
+impl Adaptive {
+    /// 校验设置
+    pub fn check(&self) -> Result<(), Error>
+    {
+        if self.min > self.max || self.max == 0 || self.decrease > 1000 {
+            return Err(Error::new(ErrorKind::InvalidData, "adaptive设置不正确"))
+        }
+        Ok(())
+    }
+}
+
+/// 第一版本地限流设置
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct V1Config {
+    pub nums: u64, //大于0时所有bot/key统一按此次数限流，0为按策略限流次数
+    pub wait: u64 //被限流时的延时(ms)，最多200
+}
+
+impl V1Config {
+    /// 延时超过200ms时按200ms
+    pub fn limited(self) -> Self
+    {
+        if self.wait > 200 {
+            error!("[Limiter:types]v1 limiter wait cannot more than 200, set it to 200");
+            return V1Config { wait: 200, ..self }
+        }
+        self
+    }
 }
 
 /// 返回信息
-#[derive(Serialize)]
+#[derive(Serialize, Default)]
 pub struct Response {
     pub total: u64,
-    pub surplus: u64
+    pub surplus: u64,
+    pub retry_after: u64, //被限流时距离下次可通过的毫秒数，0表示未知或无需等待
+    pub window: u64, //拒绝本次请求的窗口长度(ms)，日历窗口为距周期结束的时间，0表示未被拒绝
+    pub quota: u64, //拒绝本次请求的窗口序号，1为策略本身的窗口，之后依次为附加窗口，0表示未被拒绝
+    pub reset: u64, //距当前窗口重置的毫秒数，仅peek给出
+    pub error: Option<String> //redis出错时的错误信息，此时结果由失败策略给出
 }
 
-impl Default for Response {
-    fn default() -> Self {
+impl Response {
+    /// 直接放行的结果，total与surplus均为limit，用于未开启限流或按失败策略放行
+    pub fn allow(limit: u64) -> Self
+    {
         Response {
-            total: 0,
-            surplus: 0
+            total: limit,
+            surplus: limit,
+            ..Default::default()
         }
     }
+}
+
+/// redis出错时的处理策略
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum FailurePolicy {
+    // 放行
+    Allow,
+    // 拒绝
+    Deny,
+    // 改用本地限流
+    #[default]
+    Local
 }
\ No newline at end of file
@@ -1,93 +1,181 @@
 use std::collections::HashMap;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use log::{error, info};
-use serde::{Deserialize, Serialize};
-use crate::types::Response;
+use crate::types::{Response, V1Config};
 
-/// v1 初版本地限流，当redis失效就用老方法
+/// 清理过期计数的间隔(ms)
+const SWEEP: u64 = 1000;
+
+/// v1 初版本地限流，当redis失效就用老方法，被限流时由调用方按wait短暂延时
+/// 按(bot, key)分别计数，限流次数取调用方给出的策略限流次数，nums大于0时统一按nums限流
+/// 窗口结束或令牌补满的计数每隔一段时间在访问时清理
 pub struct V1 {
-    pub map: HashMap<i64, u64>,
-    pub instant: Instant,
+    pub map: HashMap<(i64, String), (u64, Instant)>, //(已使用次数, 窗口结束时间)
+    pub buckets: HashMap<(i64, String), (f64, Instant, Instant)>, //(剩余令牌, 上次补充时间, 补满时间)
     pub nums: u64,
-    pub wait: u64
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct V1Config {
-    nums: u64,
-    wait: u64
+    pub wait: u64,
+    swept: Instant //上次清理的时间
 }
 
 impl V1 {
-    pub fn new(limit: u64) -> Self
+    pub fn new(config: V1Config) -> Self
     {
         error!("[Limiter.v1]equip the v1-limiter!");
         V1 {
             map: HashMap::with_capacity(5000),
-            instant: Instant::now(),
-            nums: limit,
-            wait: 0
+            ..V1::idle(config)
         }
     }
 
-    pub async fn check(&mut self, bot_id: i64) -> Response
+    /// 未启用时的空状态，仅用于查询，不记录日志
+    pub fn idle(config: V1Config) -> Self
     {
-        let mut used = 0;
-        let mut wait = 0;
-        let elapsed = self.instant.elapsed().as_millis();
-        if elapsed > 1000 {
-            self.map.clear();
-            self.instant = Instant::now();
-        } else {
-            wait = self.wait;
-            if self.nums > 0 {
-                let num = self.map.entry(bot_id).or_insert(0);
-                if *num <= self.nums {
-                    *num += 1;
-                }
-                used = num.clone();
-            }
+        V1 {
+            map: HashMap::new(),
+            buckets: HashMap::new(),
+            nums: config.nums,
+            wait: config.wait,
+            swept: Instant::now()
+        }
+    }
+
+    /// 本地限流次数
+    pub fn limit(&self, limit: u64) -> u64
+    {
+        if self.nums > 0 { self.nums } else { limit }
+    }
+
+    /// 固定窗口，每个bot/key按自己的窗口长度(ms)计数，每次消耗cost次
+    pub fn check(&mut self, bot_id: i64, key: &str, limit: u64, window: u64, cost: u64) -> Response
+    {
+        let now = Instant::now();
+        self.sweep(now);
+        let limit = self.limit(limit);
+        let (num, end) = self.map.entry((bot_id, key.to_string())).or_insert((0, now + Duration::from_millis(window)));
+        if now > *end {
+            *num = 0;
+            *end = now + Duration::from_millis(window);
         }
-        let surplus = if self.nums >= used {
-            self.nums - used + 1
+        // 同脚本一样只有放行时才计数，剩余次数为本次之前的
+        let surplus = if *num + cost <= limit {
+            let surplus = limit - *num;
+            *num += cost;
+            surplus
         } else {
             0
         };
-        // 一个短暂延时返回效果
-        if surplus==0 {
-            tokio::time::sleep(std::time::Duration::from_millis(wait)).await
+        Response {
+            total: limit,
+            surplus,
+            retry_after: 0,
+            window: if surplus == 0 { window } else { 0 },
+            quota: if surplus == 0 { 1 } else { 0 },
+            reset: 0,
+            error: None
         }
+    }
+
+    /// 令牌桶，每个窗口(ms)补充rate个令牌，最多存burst个，每次消耗cost个
+    pub fn check_bucket(&mut self, bot_id: i64, key: &str, rate: u64, burst: u64, window: u64, cost: u64) -> Response
+    {
+        let now = Instant::now();
+        self.sweep(now);
+        let (tokens, instant, full) = self.buckets.entry((bot_id, key.to_string())).or_insert((burst as f64, now, now));
+        let elapsed = now.duration_since(*instant).as_millis() as f64;
+        *tokens = (*tokens + elapsed * rate as f64 / window as f64).min(burst as f64);
+        *instant = now;
+        let surplus = if *tokens >= cost as f64 {
+            *tokens -= cost as f64;
+            tokens.floor() as u64 + cost
+        } else {
+            0
+        };
+        // 补满后与新建的桶相同，可以清理，不补充时同redis一样保留一个窗口
+        let refill = if rate > 0 { (burst as f64 - *tokens) * window as f64 / rate as f64 } else { window as f64 };
+        *full = now + Duration::from_millis(refill.ceil() as u64);
         Response {
-            total: self.nums,
-            surplus
+            total: burst,
+            surplus,
+            retry_after: 0,
+            window: if surplus == 0 { window } else { 0 },
+            quota: if surplus == 0 { 1 } else { 0 },
+            reset: 0,
+            error: None
         }
     }
 
-    pub async fn get_limit(&self) -> String {
-        let data = V1Config {
-            nums: self.nums,
-            wait: self.wait
+    /// 查询固定窗口的剩余次数，不计数
+    pub fn peek(&self, bot_id: i64, key: &str, limit: u64, window: u64) -> Response
+    {
+        let limit = self.limit(limit);
+        let now = Instant::now();
+        let (used, reset) = match self.map.get(&(bot_id, key.to_string())) {
+            Some((num, end)) if *end > now => (*num, (*end - now).as_millis() as u64),
+            _ => (0, 0)
         };
-        serde_json::to_string(&data).unwrap()
+        let surplus = limit.saturating_sub(used);
+        Response {
+            total: limit,
+            surplus,
+            retry_after: 0,
+            window: if surplus == 0 { window } else { 0 },
+            quota: if surplus == 0 { 1 } else { 0 },
+            reset,
+            error: None
+        }
     }
 
-    pub async fn set_limit(&mut self, payload: String) -> bool {
-        let config = match serde_json::from_str::<V1Config>(&payload) {
-            Ok(c) => c,
-            Err(err) => {
-                error!("[Limiter:v1]set v1 limiter parse payload error {}", err);
-                return false
+    /// 查询令牌桶的剩余令牌，不消耗
+    pub fn peek_bucket(&self, bot_id: i64, key: &str, rate: u64, burst: u64, window: u64) -> Response
+    {
+        let tokens = match self.buckets.get(&(bot_id, key.to_string())) {
+            Some((tokens, instant, _)) => {
+                let elapsed = instant.elapsed().as_millis() as f64;
+                (*tokens + elapsed * rate as f64 / window as f64).min(burst as f64)
             }
+            None => burst as f64
         };
-        let wait = if config.wait > 200 {
-            error!("[Limiter:v1]set v1 limiter wait cannot more than 200, set it to 200");
-            200
+        let reset = if rate > 0 {
+            ((burst as f64 - tokens) * window as f64 / rate as f64).ceil() as u64
         } else {
-            config.wait
+            0
         };
+        let surplus = tokens.floor() as u64;
+        Response {
+            total: burst,
+            surplus,
+            retry_after: 0,
+            window: if surplus == 0 { window } else { 0 },
+            quota: if surplus == 0 { 1 } else { 0 },
+            reset,
+            error: None
+        }
+    }
+
+    /// 每隔SWEEP清理窗口已结束和令牌已补满的计数，避免bot/key增多后无限增长
+    fn sweep(&mut self, now: Instant)
+    {
+        if now.duration_since(self.swept) < Duration::from_millis(SWEEP) {
+            return
+        }
+        self.swept = now;
+        self.map.retain(|_, (_, end)| *end >= now);
+        self.buckets.retain(|_, (_, _, full)| *full > now);
+    }
+
+    pub fn get_limit(&self) -> V1Config {
+        V1Config {
+            nums: self.nums,
+            wait: self.wait
+        }
+    }
+
+    /// 设置限流次数与延时，返回实际生效的设置
+    pub fn set_limit(&mut self, config: V1Config) -> V1Config {
+        let config = config.limited();
         self.nums = config.nums;
-        self.wait = wait;
-        info!("[Limiter.v1]set v1 ok, {}", serde_json::to_string(&config).unwrap());
-        true
+        self.wait = config.wait;
+        info!("[Limiter.v1]set v1 ok, {:?}", config);
+        config
     }
-}
\ No newline at end of file
+}